@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use crate::Disassembler::decode;
+use crate::FileParser::Instr;
+use crate::InstructionParser::get_register_name;
+use crate::Preprocessor::pars_number;
+use crate::Simulator::{AccessKind, Machine, Step, ZERO};
+
+const HELP: &str = "\
+break LOC           stop before the instruction at LOC, a label or an address
+watch LOC [SIZE]    stop after SIZE bytes (4 by default) at LOC are written
+rwatch LOC [SIZE]   stop after SIZE bytes at LOC are read
+delete N            remove breakpoint or watchpoint N
+info break          list breakpoints and watchpoints
+step [N]            run N instructions (1 by default)
+continue            run until a breakpoint, a watchpoint, a halt or a fault
+regs                show every register
+print %REG | LOC    show a register, or the address of a label
+set %REG VALUE      change a register
+x LOC [COUNT]       show COUNT words of memory (4 by default, 1024 at most)
+where               show the current instruction
+list                show the source around the current instruction
+quit                leave the debugger
+An empty line repeats the last command.";
+
+// the most words x shows, memory is mirrored so a large count wouldn't end
+const EXAMINE_LIMIT: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Point {
+    Break(u64),
+    // address, size and whether reads (rwatch) or writes (watch) are watched
+    Watch(u64, u32, bool)
+}
+
+pub struct Debugger<'a> {
+    pub machine: Machine<'a>,
+    labels: HashMap<String, u64>,
+    // the file and line every instruction came from
+    lines: HashMap<u64, (String, u64)>,
+    sources: HashMap<String, Vec<String>>,
+    // deleted points stay as None, so the numbers don't change
+    points: Vec<Option<Point>>,
+    max_cycles: u64,
+    last_command: String
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(machine: Machine<'a>, labels: HashMap<String, u64>, instructions: &[Instr], max_cycles: u64, read_source: impl Fn(&str) -> Option<String>) -> Debugger<'a> {
+        let mut lines = HashMap::new();
+        let mut sources = HashMap::new();
+        for instr in instructions {
+            lines.insert(instr.address, (instr.file.clone(), instr.line));
+            if !sources.contains_key(&instr.file) {
+                let text = read_source(&instr.file).unwrap_or_default();
+                sources.insert(instr.file.clone(), text.split('\n').map(|l| l.trim_end_matches('\r').to_string()).collect());
+            }
+        }
+
+        Debugger { machine, labels, lines, sources, points: vec![], max_cycles, last_command: String::new() }
+    }
+
+    // Runs one command line, None means the session is over
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            l => l.to_string()
+        };
+        self.last_command = line.clone();
+
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        let result = match words.as_slice() {
+            [] => Ok(String::new()),
+            ["quit" | "q"] => return None,
+            ["help" | "h"] => Ok(HELP.to_string()),
+            ["break" | "b", location] => self.location(location).map(|a| self.add_point(Point::Break(a))),
+            ["watch", location] => self.location(location).map(|a| self.add_point(Point::Watch(a, 4, false))),
+            ["watch", location, size] => self.watch(location, size, false),
+            ["rwatch", location] => self.location(location).map(|a| self.add_point(Point::Watch(a, 4, true))),
+            ["rwatch", location, size] => self.watch(location, size, true),
+            ["delete" | "d", number] => self.delete(number),
+            ["info", "break" | "breakpoints" | "watchpoints"] => Ok(self.list_points()),
+            ["step" | "s"] => Ok(self.step(1)),
+            ["step" | "s", count] => pars_number(count).map(|c| self.step(c)),
+            ["continue" | "c"] => Ok(self.resume()),
+            ["regs"] | ["info", "registers" | "regs"] => Ok(self.machine.register_dump().trim_end().to_string()),
+            ["print" | "p", value] => self.print(value),
+            ["set", register, value] => self.set_register(register, value),
+            ["x", location] => self.examine(location, "4"),
+            ["x", location, count] => self.examine(location, count),
+            ["where" | "w"] => Ok(self.where_am_i()),
+            ["list" | "l"] => Ok(self.list()),
+            _ => Err(format!("Unknown command '{}', try help.", line))
+        };
+
+        match result {
+            Ok(o) => Some(o),
+            Err(e) => Some(format!("error: {}", e))
+        }
+    }
+
+    fn location(&self, text: &str) -> Result<u64, String> {
+        match self.labels.get(text) {
+            Some(&a) => Ok(a),
+            None => pars_number(text).map_err(|_| format!("'{}' is neither a label nor an address.", text))
+        }
+    }
+
+    fn register(&self, text: &str) -> Result<usize, String> {
+        let name = text.trim_start_matches('%');
+        match (0..64).find(|&l| get_register_name(l).is_some_and(|n| n.eq_ignore_ascii_case(name))) {
+            Some(l) => Ok(l as usize),
+            None => Err(format!("Unknown register '{}'.", text))
+        }
+    }
+
+    fn add_point(&mut self, point: Point) -> String {
+        self.points.push(Some(point.clone()));
+        format!("{} {}", self.points.len(), self.describe(&point))
+    }
+
+    fn describe(&self, point: &Point) -> String {
+        match point {
+            Point::Break(address) => format!("breakpoint at {}", self.symbolic(*address)),
+            Point::Watch(address, size, false) => format!("watchpoint on writes of {} byte(s) at {}", size, self.symbolic(*address)),
+            Point::Watch(address, size, true) => format!("watchpoint on reads of {} byte(s) at {}", size, self.symbolic(*address))
+        }
+    }
+
+    fn watch(&mut self, location: &str, size: &str, read: bool) -> Result<String, String> {
+        let address = self.location(location)?;
+        let size = pars_number(size)?;
+        if size == 0 || size > u32::MAX as u64 {
+            return Err(format!("Invalid watchpoint size {}.", size));
+        }
+
+        Ok(self.add_point(Point::Watch(address, size as u32, read)))
+    }
+
+    fn delete(&mut self, number: &str) -> Result<String, String> {
+        let number = pars_number(number)? as usize;
+        match self.points.get_mut(number.wrapping_sub(1)) {
+            Some(point @ Some(_)) => {
+                *point = None;
+                Ok(format!("deleted {}", number))
+            },
+            _ => Err(format!("There is no breakpoint or watchpoint {}.", number))
+        }
+    }
+
+    fn list_points(&self) -> String {
+        let result = self.points.iter().enumerate()
+            .filter_map(|(i, p)| p.as_ref().map(|p| format!("{} {}", i + 1, self.describe(p))))
+            .collect::<Vec<String>>();
+        if result.is_empty() {
+            return String::from("no breakpoints or watchpoints");
+        }
+
+        result.join("\n")
+    }
+
+    // the nearest label at or before the address
+    fn symbolic(&self, address: u64) -> String {
+        let label = self.labels.iter().filter(|(n, &a)| a <= address && !n.starts_with('@')).max_by(|a, b| (a.1, b.0).cmp(&(b.1, a.0)));
+        match label {
+            Some((name, &a)) if a == address => format!("{:#010X} <{}>", address, name),
+            Some((name, &a)) if address - a < 0x1000 => format!("{:#010X} <{}+{:#X}>", address, name, address - a),
+            _ => format!("{:#010X}", address)
+        }
+    }
+
+    fn where_am_i(&self) -> String {
+        let address = self.machine.pc();
+        let source = match self.lines.get(&address) {
+            Some((file, line)) => {
+                let text = self.sources.get(file).and_then(|s| s.get(*line as usize - 1)).map(|l| l.trim()).unwrap_or_default();
+                format!("{}:{}  {}", file, line, text)
+            },
+            None => match self.machine.memory.read(address, 4) {
+                Ok(word) => decode(word, self.machine.isa(), &[]).unwrap_or_else(|| format!("{:#010X}", word)),
+                Err(e) => e
+            }
+        };
+
+        format!("{}  {}", self.symbolic(address), source)
+    }
+
+    fn list(&self) -> String {
+        let (file, line) = match self.lines.get(&self.machine.pc()) {
+            Some(l) => l,
+            None => return String::from("no source for the current instruction")
+        };
+        let source = match self.sources.get(file) {
+            Some(s) => s,
+            None => return String::from("no source for the current instruction")
+        };
+
+        let first = line.saturating_sub(5).max(1);
+        let last = (line + 5).min(source.len() as u64);
+        (first..=last).map(|l| {
+            let marker = if l == *line { "=>" } else { "  " };
+            format!("{} {:>4} | {}", marker, l, source[l as usize - 1])
+        }).collect::<Vec<String>>().join("\n")
+    }
+
+    fn print(&self, value: &str) -> Result<String, String> {
+        if value.starts_with('%') {
+            let label = self.register(value)?;
+            let content = if label == ZERO { 0 } else { self.machine.registers[label] };
+            return Ok(format!("{} = {:#010X} ({})", value, content, content));
+        }
+
+        self.location(value).map(|a| format!("{} = {}", value, self.symbolic(a)))
+    }
+
+    fn set_register(&mut self, register: &str, value: &str) -> Result<String, String> {
+        let label = self.register(register)?;
+        let value = pars_number(value)?;
+        if value > u32::MAX as u64 {
+            return Err(format!("{:#X} doesn't fit in a register.", value));
+        }
+
+        if label != ZERO {
+            self.machine.registers[label] = value as u32;
+        }
+        self.print(register)
+    }
+
+    fn examine(&self, location: &str, count: &str) -> Result<String, String> {
+        let address = self.location(location)?;
+        let count = pars_number(count)?;
+        if count == 0 || count > EXAMINE_LIMIT {
+            return Err(format!("Invalid count {}, x shows 1 to {} words.", count, EXAMINE_LIMIT));
+        }
+
+        let mut result = vec![];
+        for index in 0..count {
+            let word_address = address + index * 4;
+            match self.machine.memory.read(word_address, 4) {
+                Ok(w) => result.push(format!("{:#010X}: {:08X}", word_address, w)),
+                Err(e) => {
+                    result.push(e);
+                    break;
+                }
+            }
+        }
+
+        Ok(result.join("\n"))
+    }
+
+    // the watchpoint an access of the step hits, as a message
+    fn watch_hit(&self, step: &Step) -> Option<String> {
+        for (index, point) in self.points.iter().enumerate() {
+            let (address, size, read) = match point {
+                Some(Point::Watch(a, s, r)) => (*a, *s as u64, *r),
+                _ => continue
+            };
+
+            for access in step.accesses.iter() {
+                let kind_matches = match access.kind {
+                    AccessKind::Fetch => false,
+                    AccessKind::Read => read,
+                    AccessKind::Write => !read
+                };
+                if kind_matches && access.address < address + size && address < access.address + access.size as u64 {
+                    let verb = if read { "read" } else { "written" };
+                    return Some(format!("watchpoint {}: {:#010X} {} with {:#X} by {}", index + 1, access.address, verb, access.value, self.symbolic(step.address)));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn at_breakpoint(&self) -> Option<usize> {
+        let pc = self.machine.pc();
+        self.points.iter().position(|p| *p == Some(Point::Break(pc)))
+    }
+
+    // One instruction, Err is the message that stops stepping
+    fn single_step(&mut self) -> Result<(), String> {
+        let address = self.machine.pc();
+        let step = match self.machine.step() {
+            Ok(s) => s,
+            Err(e) => return Err(format!("fault at {}: {}", self.symbolic(address), e))
+        };
+
+        if let Some(hit) = self.watch_hit(&step) {
+            return Err(hit);
+        }
+        if self.machine.pc() == address {
+            return Err(format!("halted at {}", self.symbolic(address)));
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, count: u64) -> String {
+        for _ in 0..count {
+            if let Err(message) = self.single_step() {
+                return format!("{}\n{}", message, self.where_am_i());
+            }
+        }
+
+        self.where_am_i()
+    }
+
+    // The breakpoint at the current instruction doesn't stop it, so continue leaves it
+    fn resume(&mut self) -> String {
+        let limit = self.machine.cycles + self.max_cycles;
+        loop {
+            if let Err(message) = self.single_step() {
+                return format!("{}\n{}", message, self.where_am_i());
+            }
+            if let Some(index) = self.at_breakpoint() {
+                return format!("breakpoint {}\n{}", index + 1, self.where_am_i());
+            }
+            if self.machine.cycles >= limit {
+                return format!("stopped after {} cycles\n{}", self.max_cycles, self.where_am_i());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InstructionParser::encode_instruction;
+    use crate::InstructionSet::InstructionSet;
+    use crate::Simulator::{Memory, Region};
+
+    const SOURCE: &str = "start:\n    LOAD8 %a0, [0x40]\n    LOAD8 %a1, [7]\nloop:\n    STORE8 %a1, %a0\n    ADD %a1, %a1, [1]\n    JMP loop";
+
+    fn debugger(isa: &InstructionSet) -> Debugger<'_> {
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x1000, true)]).unwrap();
+        let labels = HashMap::from([(String::from("start"), 0x100), (String::from("loop"), 0x108)]);
+        let program = [("LOAD8 %a0, [0x40]", 2), ("LOAD8 %a1, [7]", 3), ("STORE8 %a1, %a0", 5), ("ADD %a1, %a1, [1]", 6), ("JMP [0x108]", 7)];
+
+        let mut instructions = vec![];
+        for (index, (text, line)) in program.iter().enumerate() {
+            let address = 0x100 + index as u64 * 4;
+            let (mnemonic, operands) = text.split_once(' ').unwrap();
+            let word = encode_instruction(isa.get(mnemonic).unwrap(), operands.split(", ").collect(), HashMap::new()).unwrap();
+            memory.load(address, &word.to_le_bytes()).unwrap();
+            instructions.push(Instr { data: text.to_string(), statement: crate::Syntax::parse_statement(text).unwrap(), address, file: String::from("loop.maasm"), line: *line, column: 5, scope: Default::default(), included_from: vec![], replacements: vec![] });
+        }
+
+        let machine = Machine::new(isa, memory, 0x100);
+        Debugger::new(machine, labels, &instructions, 1000, |_| Some(SOURCE.to_string()))
+    }
+
+    #[test]
+    fn test_step_and_inspect() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut debugger = debugger(&isa);
+
+        assert_eq!(debugger.command("where"), Some(String::from("0x00000100 <start>  loop.maasm:2  LOAD8 %a0, [0x40]")));
+        assert_eq!(debugger.command("step 2"), Some(String::from("0x00000108 <loop>  loop.maasm:5  STORE8 %a1, %a0")));
+        assert_eq!(debugger.command("print %A1"), Some(String::from("%A1 = 0x00000007 (7)")));
+        assert_eq!(debugger.command(""), Some(String::from("%A1 = 0x00000007 (7)")));
+        assert_eq!(debugger.command("step"), Some(String::from("0x0000010C <loop+0x4>  loop.maasm:6  ADD %a1, %a1, [1]")));
+        assert_eq!(debugger.command("x 0x40 1"), Some(String::from("0x00000040: 00000007")));
+        assert_eq!(debugger.command("set %a1 0x20"), Some(String::from("%a1 = 0x00000020 (32)")));
+        assert_eq!(debugger.command("print loop"), Some(String::from("loop = 0x00000108 <loop>")));
+        assert_eq!(debugger.command("list"), Some(String::from(concat!(
+            "      1 | start:\n",
+            "      2 |     LOAD8 %a0, [0x40]\n",
+            "      3 |     LOAD8 %a1, [7]\n",
+            "      4 | loop:\n",
+            "      5 |     STORE8 %a1, %a0\n",
+            "=>    6 |     ADD %a1, %a1, [1]\n",
+            "      7 |     JMP loop"
+        ))));
+        assert_eq!(debugger.command("print %q0"), Some(String::from("error: Unknown register '%q0'.")));
+        assert_eq!(debugger.command("frobnicate"), Some(String::from("error: Unknown command 'frobnicate', try help.")));
+        assert_eq!(debugger.command("quit"), None);
+    }
+
+    #[test]
+    fn test_examine_limit() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut debugger = debugger(&isa);
+
+        // the memory is mirrored, so this would never reach an unmapped byte
+        assert_eq!(debugger.command("x 0 0xFFFFFFFFFFFF"), Some(String::from("error: Invalid count 281474976710655, x shows 1 to 1024 words.")));
+        assert_eq!(debugger.command("x 0 1024").map(|o| o.lines().count()), Some(1024));
+        assert_eq!(debugger.command("x 0 0"), Some(String::from("error: Invalid count 0, x shows 1 to 1024 words.")));
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut debugger = debugger(&isa);
+
+        assert_eq!(debugger.command("break loop"), Some(String::from("1 breakpoint at 0x00000108 <loop>")));
+        assert_eq!(debugger.command("continue"), Some(String::from("breakpoint 1\n0x00000108 <loop>  loop.maasm:5  STORE8 %a1, %a0")));
+        assert_eq!(debugger.command("watch 0x40 1"), Some(String::from("2 watchpoint on writes of 1 byte(s) at 0x00000040")));
+        assert_eq!(debugger.command("continue"), Some(String::from(
+            "watchpoint 2: 0x00000040 written with 0x7 by 0x00000108 <loop>\n0x0000010C <loop+0x4>  loop.maasm:6  ADD %a1, %a1, [1]"
+        )));
+        assert_eq!(debugger.command("continue"), Some(String::from("breakpoint 1\n0x00000108 <loop>  loop.maasm:5  STORE8 %a1, %a0")));
+        assert_eq!(debugger.command("info break"), Some(String::from(
+            "1 breakpoint at 0x00000108 <loop>\n2 watchpoint on writes of 1 byte(s) at 0x00000040"
+        )));
+        assert_eq!(debugger.command("delete 1"), Some(String::from("deleted 1")));
+        assert_eq!(debugger.command("delete 1"), Some(String::from("error: There is no breakpoint or watchpoint 1.")));
+        assert_eq!(debugger.command("delete 2"), Some(String::from("deleted 2")));
+        assert_eq!(debugger.command("continue"), Some(String::from("stopped after 1000 cycles\n0x00000108 <loop>  loop.maasm:5  STORE8 %a1, %a0")));
+    }
+}
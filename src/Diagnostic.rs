@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::read_to_string;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning")
+        }
+    }
+}
+
+// line and column start at 1, 0 means the diagnostic isn't bound to a position.
+// length is the number of characters to underline, 0 puts a single caret at the column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: u64,
+    pub column: u64,
+    pub length: u64,
+    pub message: String,
+    pub notes: Vec<String>
+}
+
+impl Diagnostic {
+    pub fn error(file: &str, line: u64, column: u64, message: String) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, file: file.to_string(), line, column, length: 0, message, notes: vec![] }
+    }
+
+    pub fn warning(file: &str, line: u64, column: u64, message: String) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, file: file.to_string(), line, column, length: 0, message, notes: vec![] }
+    }
+
+    pub fn with_length(mut self, length: u64) -> Diagnostic {
+        self.length = length;
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // source is the text of the line the diagnostic points to, if it could be read
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut result = format!("{}: {}\n", self.severity, self.message);
+
+        if self.line == 0 {
+            result += &format!(" --> {}\n", self.file);
+        } else if self.column == 0 {
+            result += &format!(" --> {}:{}\n", self.file, self.line);
+        } else {
+            result += &format!(" --> {}:{}:{}\n", self.file, self.line, self.column);
+        }
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        if let (Some(source), true) = (source, self.line != 0) {
+            result += &format!("{} |\n", gutter);
+            result += &format!("{} | {}\n", self.line, expand_tabs(source));
+            if self.column != 0 {
+                let before = source.chars().take(self.column as usize - 1).collect::<String>();
+                let marked = source.chars().skip(self.column as usize - 1).take(self.length.max(1) as usize).collect::<String>();
+                let offset = expand_tabs(&before).chars().count();
+                let width = expand_tabs(&marked).chars().count().max(1);
+                result += &format!("{} | {}{}\n", gutter, " ".repeat(offset), "^".repeat(width));
+            }
+        }
+
+        for note in self.notes.iter() {
+            result += &format!("{} = note: {}\n", gutter, note);
+        }
+
+        result
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}: {}", self.file, self.severity, self.message)
+        } else if self.column == 0 {
+            write!(f, "{}:{}: {}: {}", self.file, self.line, self.severity, self.message)
+        } else {
+            write!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.column, self.severity, self.message)
+        }
+    }
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', "    ")
+}
+
+pub fn print_all(diagnostics: &[Diagnostic]) {
+    let mut sources: HashMap<String, Option<Vec<String>>> = HashMap::new();
+
+    for diagnostic in diagnostics {
+        let lines = sources.entry(diagnostic.file.clone()).or_insert_with(|| {
+            File::open(&diagnostic.file)
+                .and_then(read_to_string)
+                .ok()
+                .map(|s| s.split('\n').map(|l| l.trim_end_matches('\r').to_string()).collect())
+        });
+        let source = lines.as_ref().and_then(|l| l.get(diagnostic.line.wrapping_sub(1) as usize)).map(|l| l.as_str());
+        eprintln!("{}", diagnostic.render(source));
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        eprintln!("{} error(s), {} warning(s) emitted", errors, warnings);
+    } else if warnings > 0 {
+        eprintln!("{} warning(s) emitted", warnings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_with_source() {
+        let diagnostic = Diagnostic::error("test.maasm", 12, 11, String::from("LOAD8: target register can't be PC"))
+            .with_length(3)
+            .with_note(String::from("valid registers for this slot are A0, A1"));
+
+        assert_eq!(diagnostic.render(Some("    LOAD8 %PC, [0]")), concat!(
+            "error: LOAD8: target register can't be PC\n",
+            " --> test.maasm:12:11\n",
+            "   |\n",
+            "12 |     LOAD8 %PC, [0]\n",
+            "   |           ^^^\n",
+            "   = note: valid registers for this slot are A0, A1\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_tabs_and_no_source() {
+        let diagnostic = Diagnostic::warning("test.maasm", 3, 2, String::from("address 0x3 isn't aligned to 4 bytes."));
+        assert_eq!(diagnostic.render(Some("\t.AT 0x3")), concat!(
+            "warning: address 0x3 isn't aligned to 4 bytes.\n",
+            " --> test.maasm:3:2\n",
+            "  |\n",
+            "3 |     .AT 0x3\n",
+            "  |     ^\n"
+        ));
+
+        let diagnostic = Diagnostic::error("missing.maasm", 0, 0, String::from("No such file or directory"));
+        assert_eq!(diagnostic.render(None), "error: No such file or directory\n --> missing.maasm\n");
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use crate::InstructionParser::{encode_instruction, get_register_name, OPCODE_SHIFT};
+use crate::InstructionSet::{Constraint, FormKind, InstructionSet, OperandForm};
+
+enum Operand {
+    Register(&'static str),
+    Immediate(u32),
+    // an immediate wide enough to hold an address, printed as a label when one matches
+    Address(u32)
+}
+
+// Reads a symbol map written with --map, in the text or the JSON format.
+// The result is sorted by address, symbols at the same address by name.
+pub fn pars_symbols(map: &str) -> Result<Vec<(String, u64)>, String> {
+    let mut result = vec![];
+
+    if map.trim_start().starts_with('{') {
+        let json: serde_json::Value = match serde_json::from_str(map) {
+            Ok(j) => j,
+            Err(e) => return Err(format!("Invalid symbol map: {}", e))
+        };
+        let symbols = match json["symbols"].as_array() {
+            Some(s) => s,
+            None => return Err(String::from("Invalid symbol map: missing 'symbols'."))
+        };
+        for symbol in symbols {
+            match (symbol["name"].as_str(), symbol["address"].as_u64()) {
+                (Some(n), Some(a)) => result.push((n.to_string(), a)),
+                _ => return Err(format!("Invalid symbol map entry: {}", symbol))
+            }
+        }
+    } else {
+        for line in map.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let address = match u64::from_str_radix(fields[0], 16) {
+                Ok(a) => a,
+                Err(e) => return Err(format!("Invalid symbol map line '{}': {}", line, e))
+            };
+            match fields.last() {
+                Some(name) if fields.len() > 1 => result.push((name.to_string(), address)),
+                _ => return Err(format!("Invalid symbol map line '{}'.", line))
+            }
+        }
+    }
+
+    // the text format lists every symbol twice
+    result.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    result.dedup();
+    Ok(result)
+}
+
+// The forms are tried in the order of the ISA description. A text is only returned as is
+// when assembling it gives the same word again, otherwise the first form that fits is shown.
+pub fn decode(word: u32, isa: &InstructionSet, symbols: &[(String, u64)]) -> Option<String> {
+    let (texts, first) = candidates(word, isa, symbols);
+    texts.into_iter().next().or(first)
+}
+
+// Other instructions that assemble to the word decode returns, the CPU can't tell them apart
+// from it. check-isa lists the forms where this can happen.
+pub fn alternatives(word: u32, isa: &InstructionSet, symbols: &[(String, u64)]) -> Vec<String> {
+    candidates(word, isa, symbols).0.into_iter().skip(1).collect()
+}
+
+// Every instruction that assembles to the word, as the text of its first form that does,
+// and the first text that fits the word for when none of them does.
+fn candidates(word: u32, isa: &InstructionSet, symbols: &[(String, u64)]) -> (Vec<String>, Option<String>) {
+    let labels = symbols.iter().cloned().collect::<HashMap<String, u64>>();
+    let opcode = word >> OPCODE_SHIFT;
+    let mut texts = vec![];
+    let mut first = None;
+
+    for inst in isa.instructions() {
+        'forms: for form in inst.forms.iter().filter(|f| f.opcode == opcode) {
+            let operands = match decode_form(word, form, &inst.constraint) {
+                Some(o) => o,
+                None => continue
+            };
+
+            let operands = operands.iter().map(|o| match o {
+                Operand::Register(r) => format!("%{}", r.to_lowercase()),
+                Operand::Immediate(i) => format!("[{:#X}]", i),
+                Operand::Address(a) => match symbols.iter().find(|s| s.1 == *a as u64) {
+                    Some((name, _)) => name.clone(),
+                    None => format!("[{:#X}]", a)
+                }
+            }).collect::<Vec<String>>();
+
+            // arguments the form takes but doesn't encode, an earlier form with the same
+            // argument count may take the immediate placeholder, so a register is tried too
+            for placeholder in ["[0]", "%zero"] {
+                let mut operands = operands.clone();
+                while operands.len() < form.args {
+                    operands.push(String::from(placeholder));
+                }
+
+                if encode_instruction(inst, operands.iter().map(|o| o.as_str()).collect(), labels.clone()) == Some(word) {
+                    texts.push(instruction_text(&inst.name, &operands));
+                    break 'forms;
+                }
+                if first.is_none() {
+                    first = Some(instruction_text(&inst.name, &operands));
+                }
+            }
+        }
+    }
+
+    (texts, first)
+}
+
+fn instruction_text(name: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        return name.to_string();
+    }
+
+    format!("{} {}", name, operands.join(", "))
+}
+
+// Whether the form could have produced the word, with operands that its constraints allow
+pub fn decodes_as(word: u32, form: &OperandForm, constraint: &Constraint) -> bool {
+    word >> OPCODE_SHIFT == form.opcode && decode_form(word, form, constraint).is_some()
+}
+
+fn decode_form(word: u32, form: &OperandForm, constraint: &Constraint) -> Option<Vec<Operand>> {
+    let target = || register(word, 16, &constraint.target_invalid_reg);
+    let source_0 = || register(word, 10, &constraint.source_0_invalid_reg);
+    let source_1 = || register(word, 4, &constraint.source_1_invalid_reg);
+    let immediate_0 = |value: u32| immediate(value, constraint.immediate_0_number_max);
+    let immediate_1 = |value: u32| immediate(value, constraint.immediate_1_number_max);
+
+    // bits that the form doesn't use must be zero
+    return match form.kind {
+        FormKind::I => Some(vec![immediate_0(word & 0x3FFFFF)?]),
+        FormKind::S if word & 0xFFFF == 0 => Some(vec![target()?]),
+        FormKind::Ss if word & 0x3F000F == 0 => Some(vec![source_0()?, source_1()?]),
+        FormKind::Ti => Some(vec![target()?, immediate_0(word & 0xFFFF)?]),
+        FormKind::Ts if word & 0x3FF == 0 => Some(vec![target()?, source_0()?]),
+        FormKind::Tsi => Some(vec![target()?, source_0()?, immediate_0(word & 0x3FF)?]),
+        FormKind::Tss if word & 0xF == 0 => Some(vec![target()?, source_0()?, source_1()?]),
+        FormKind::Tii if word & 0xF == 0 => Some(vec![target()?, immediate_0((word >> 10) & 0x3F)?, immediate_1((word >> 4) & 0x3F)?]),
+        _ => None
+    }
+}
+
+fn register(word: u32, shift: u32, invalid_reg: &[String]) -> Option<Operand> {
+    let name = get_register_name(((word >> shift) & 0x3F) as u8)?;
+    if invalid_reg.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+        return None;
+    }
+
+    Some(Operand::Register(name))
+}
+
+fn immediate(value: u32, max: u32) -> Option<Operand> {
+    if value > max {
+        return None;
+    }
+
+    if max > 0xFFFF {
+        Some(Operand::Address(value))
+    } else {
+        Some(Operand::Immediate(value))
+    }
+}
+
+// Zero words are skipped, an .AT line is written wherever the listing continues after
+// a gap, so the result can be assembled again at the same addresses.
+pub fn disassemble(bytes: &[u8], base: u64, isa: &InstructionSet, symbols: &[(String, u64)]) -> String {
+    let mut result = String::new();
+    let mut gap = true;
+
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let address = base + index as u64 * 4;
+        if chunk.len() < 4 {
+            result += &format!("; {} byte(s) at {:#X} don't make a whole word\n", chunk.len(), address);
+            break;
+        }
+
+        let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        if word == 0 {
+            gap = true;
+            continue;
+        }
+
+        let text = match decode(word, isa, symbols) {
+            Some(t) => t,
+            None => {
+                result += &format!("; {:08X}: {:08X} isn't an instruction\n", address, word);
+                gap = true;
+                continue;
+            }
+        };
+
+        if gap {
+            if !result.is_empty() {
+                result.push('\n');
+            }
+            result += &format!(".AT {:#X}\n", address);
+            gap = false;
+        }
+        for (name, _) in symbols.iter().filter(|s| s.1 == address) {
+            result += &format!("{}:\n", name);
+        }
+        let others = alternatives(word, isa, symbols);
+        if others.is_empty() {
+            result += &format!("    {:<40}; {:08X}: {:08X}\n", text, address, word);
+        } else {
+            result += &format!("    {:<40}; {:08X}: {:08X}, also {}\n", text, address, word, others.join(" or "));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let isa = InstructionSet::load(None).unwrap();
+        let symbols = vec![(String::from("check_ram"), 0xF0004)];
+
+        assert_eq!(decode(0x004300FF, &isa, &symbols), Some(String::from("LOAD8 %a2, [0xFF]")));
+        assert_eq!(decode(0x90851000, &isa, &symbols), Some(String::from("EQ %ar0, %a3, [0x0]")));
+        assert_eq!(decode(0x90C51020, &isa, &symbols), Some(String::from("EQ %ar0, %a3, %a1")));
+        assert_eq!(decode(0xC04C0000, &isa, &symbols), Some(String::from("JMP %b1")));
+        assert_eq!(decode(0xC00F0004, &isa, &symbols), Some(String::from("JMP check_ram")));
+        assert_eq!(decode(0xC03F0000, &isa, &symbols), Some(String::from("JMP [0x3F0000]")));
+        assert_eq!(decode(0x00830001, &isa, &symbols), Some(String::from("LOAD8 %a2, [0x1], [0]")));
+        assert_eq!(decode(0xC0840000, &isa, &symbols), Some(String::from("LOAD8 %a3, %zero")));
+        // arguments a form doesn't encode come back as the placeholder, whatever was written
+        let load8 = isa.get("LOAD8").unwrap();
+        assert_eq!(encode_instruction(load8, vec!["%a3", "%a0"], HashMap::new()), Some(0xC0840000));
+        assert_eq!(encode_instruction(load8, vec!["%a2", "[0x1]", "[5]"], HashMap::new()), Some(0x00830001));
+        // ADD and SUB share their opcodes, OJMP to 0 is the s form of LOAD8
+        assert_eq!(decode(0x80410830, &isa, &symbols), Some(String::from("ADD %a0, %a1, %a2")));
+        assert_eq!(alternatives(0x80410830, &isa, &symbols), vec![String::from("SUB %a0, %a1, %a2")]);
+        assert_eq!(alternatives(0xC0840000, &isa, &symbols), vec![String::from("OJMP %a3, [0x0]")]);
+        assert_eq!(alternatives(0x004300FF, &isa, &symbols), Vec::<String>::new());
+        // ZERO isn't a valid target of LOAD8
+        assert_eq!(decode(0x00400001, &isa, &symbols), None);
+        assert_eq!(decode(0x00000000, &isa, &symbols), None);
+        // a register field that names no register
+        assert_eq!(decode(0xC07E0000, &isa, &symbols), None);
+    }
+
+    #[test]
+    fn test_pars_symbols() {
+        let text = "; symbols by address\n000F0000  label          start\n00002000  data        6  buffer\n\n; symbols by name\n00002000  data        6  buffer\n000F0000  label          start\n";
+        let expected = vec![(String::from("buffer"), 0x2000), (String::from("start"), 0xF0000)];
+        assert_eq!(pars_symbols(text), Ok(expected.clone()));
+
+        let json = "{ \"symbols\": [{ \"name\": \"start\", \"address\": 983040, \"kind\": \"label\", \"size\": null }, { \"name\": \"buffer\", \"address\": 8192, \"kind\": \"data\", \"size\": 6 }] }";
+        assert_eq!(pars_symbols(json), Ok(expected));
+
+        assert!(pars_symbols("xyz start\n").is_err());
+        assert!(pars_symbols("{ \"symbols\": 1 }").is_err());
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut bytes = vec![0; 8];
+        bytes.extend_from_slice(&0xC0000108_u32.to_le_bytes());
+        bytes.extend_from_slice(&0x004300FF_u32.to_le_bytes());
+        bytes.extend_from_slice(&0xFFFFFFFF_u32.to_le_bytes());
+        bytes.extend_from_slice(&0x004300FF_u32.to_le_bytes());
+        bytes.extend_from_slice(&0x80410830_u32.to_le_bytes());
+        bytes.push(1);
+        let symbols = vec![(String::from("loop"), 0x108)];
+
+        assert_eq!(disassemble(&bytes, 0x100, &isa, &symbols), concat!(
+            ".AT 0x108\n",
+            "loop:\n",
+            "    JMP loop                                ; 00000108: C0000108\n",
+            "    LOAD8 %a2, [0xFF]                       ; 0000010C: 004300FF\n",
+            "; 00000110: FFFFFFFF isn't an instruction\n",
+            "\n",
+            ".AT 0x114\n",
+            "    LOAD8 %a2, [0xFF]                       ; 00000114: 004300FF\n",
+            "    ADD %a0, %a1, %a2                       ; 00000118: 80410830, also SUB %a0, %a1, %a2\n",
+            "; 1 byte(s) at 0x11C don't make a whole word\n"
+        ));
+    }
+
+    // xorshift, so the cases are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len() as u64) as usize]
+        }
+
+        fn immediate(&mut self, max: u32) -> u32 {
+            match self.below(4) {
+                0 => 0,
+                1 => max,
+                _ => self.below(max as u64 + 1) as u32
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let isa = InstructionSet::load(None).unwrap();
+        let registers = (0..64).filter_map(get_register_name).collect::<Vec<&str>>();
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+
+        for inst in isa.instructions() {
+            let constraint = &inst.constraint;
+            let valid = |invalid: &Vec<String>| registers.iter().filter(|r| !invalid.iter().any(|i| i.eq_ignore_ascii_case(r))).copied().collect::<Vec<&str>>();
+            let target = valid(&constraint.target_invalid_reg);
+            let source_0 = valid(&constraint.source_0_invalid_reg);
+            let source_1 = valid(&constraint.source_1_invalid_reg);
+
+            for form in inst.forms.iter() {
+                for _ in 0..200 {
+                    let mut symbols = vec![];
+                    let register = |rng: &mut Rng, slot: &[&str]| format!("%{}", rng.pick(slot).to_lowercase());
+                    let mut immediate = |rng: &mut Rng, max: u32| {
+                        let value = rng.immediate(max);
+                        if max > 0xFFFF && rng.below(4) == 0 {
+                            symbols.push((String::from("target"), value as u64));
+                            return String::from("target");
+                        }
+                        format!("[{:#X}]", value)
+                    };
+
+                    // a ti immediate is 16 bits wide, larger values run into the target register
+                    let ti_max = constraint.immediate_0_number_max.min(0xFFFF);
+                    let mut operands = match form.kind {
+                        FormKind::I => vec![immediate(&mut rng, constraint.immediate_0_number_max)],
+                        FormKind::S => vec![register(&mut rng, &target)],
+                        FormKind::Ss => vec![register(&mut rng, &source_0), register(&mut rng, &source_1)],
+                        FormKind::Ti => vec![register(&mut rng, &target), immediate(&mut rng, ti_max)],
+                        FormKind::Ts => vec![register(&mut rng, &target), register(&mut rng, &source_0)],
+                        FormKind::Tsi => vec![register(&mut rng, &target), register(&mut rng, &source_0), immediate(&mut rng, constraint.immediate_0_number_max)],
+                        FormKind::Tss => vec![register(&mut rng, &target), register(&mut rng, &source_0), register(&mut rng, &source_1)],
+                        FormKind::Tii => vec![register(&mut rng, &target), immediate(&mut rng, constraint.immediate_0_number_max), immediate(&mut rng, constraint.immediate_1_number_max)]
+                    };
+                    let labels = symbols.iter().cloned().collect::<HashMap<String, u64>>();
+
+                    // the operands a form doesn't encode get the placeholder that selects this form,
+                    // anything else written there is lost, see test_decode
+                    let mut word = None;
+                    for placeholder in ["[0]", "%zero"] {
+                        let mut padded = operands.clone();
+                        while padded.len() < form.args {
+                            padded.push(String::from(placeholder));
+                        }
+                        let encoded = encode_instruction(inst, padded.iter().map(|o| o.as_str()).collect(), labels.clone());
+                        if encoded.map(|w| w >> OPCODE_SHIFT) == Some(form.opcode) {
+                            operands = padded;
+                            word = encoded;
+                            break;
+                        }
+                    }
+                    let text = instruction_text(&inst.name, &operands);
+                    let word = match word {
+                        Some(w) => w,
+                        None => panic!("'{}' isn't encoded with the '{}' form", text, form.kind.name())
+                    };
+
+                    let decoded = match decode(word, &isa, &symbols) {
+                        Some(d) => d,
+                        None => panic!("'{}' ({:08X}) can't be decoded", text, word)
+                    };
+                    let shown = decoded.split_whitespace().next().unwrap_or_default();
+                    let reencoded = encode_instruction(isa.get(shown).unwrap(), decoded.split_once(' ').map(|(_, o)| o.split(", ").collect()).unwrap_or_default(), labels.clone());
+                    assert_eq!(reencoded, Some(word), "'{}' was decoded to '{}'", text, decoded);
+
+                    // the one text that doesn't come back as written: a tss form with ZERO as
+                    // source 1 is the word of the ts form of the same instruction, which is shown
+                    let has_ts = inst.forms.iter().any(|f| f.kind == FormKind::Ts && f.opcode == form.opcode);
+                    let expected = match text.strip_suffix(", %zero") {
+                        Some(t) if form.kind == FormKind::Tss && has_ts => t.to_string(),
+                        _ => text.clone()
+                    };
+                    // another instruction with the same word is listed after the one shown
+                    if shown == inst.name {
+                        assert_eq!(decoded, expected, "'{}' ({:08X}) was decoded to '{}'", text, word, decoded);
+                    } else {
+                        assert!(alternatives(word, &isa, &symbols).contains(&expected), "'{}' ({:08X}) was decoded to '{}' without mentioning it", text, word, decoded);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,76 +1,462 @@
 use std::fs::File;
 use std::io::read_to_string;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::Diagnostic::Diagnostic;
+use crate::Preprocessor::{self, Data, Replacement, Settings};
+use crate::Syntax::{self, Span, Statement, Token, TokenKind};
 
 
+#[derive(Clone)]
 pub struct Instr {
     pub data: String,
+    // data parsed, a Statement::Instruction
+    pub statement: Statement,
     pub address: u64,
+    pub file: String,
+    pub line: u64,
+    pub column: u64,
+    pub scope: Scope,
+    pub included_from: Vec<(String, u64)>,
+    // the .DEF values in data, diagnostics point into the line as it was written
+    pub replacements: Vec<Replacement>
+}
+
+impl Instr {
+    // column and length in the written line of a span of data
+    pub fn span(&self, start: u64, length: u64) -> (u64, u64) {
+        let (start, length) = Preprocessor::original_span(&self.replacements, start, length);
+        (self.column + start, length)
+    }
+}
+
+// What .loop, @f and @b stand for at a place in the source. An anonymous label @@ is stored
+// as @@1, @@2, ... in the order they are defined.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    // the last global label, the owner of the local labels
+    pub global: Option<String>,
+    // the number of @@ labels before this place
+    pub anonymous: usize
+}
+
+impl Scope {
+    // the name a label definition is stored under
+    fn define(&mut self, name: &str) -> Result<String, String> {
+        if name == "@@" {
+            self.anonymous += 1;
+            return Ok(format!("@@{}", self.anonymous));
+        }
+        if name.starts_with('@') {
+            return Err(format!("'{}' can't be defined, anonymous labels are defined as '@@:'.", name));
+        }
+        if let Some(local) = name.strip_prefix('.') {
+            return match &self.global {
+                Some(g) => Ok(format!("{}.{}", g, local)),
+                None => Err(format!("Local label '{}' has no global label before it.", name))
+            };
+        }
+        if name.contains('.') {
+            return Err(format!("'{}' can't be defined, local labels are defined as '.{}:' after their global label.", name, name.rsplit('.').next().unwrap_or_default()));
+        }
+
+        self.global = Some(name.to_string());
+        Ok(name.to_string())
+    }
+
+    // the label name is used for, None when it is used as it is
+    pub fn resolve(&self, name: &str) -> Option<String> {
+        match name {
+            "@b" | "@B" if self.anonymous > 0 => Some(format!("@@{}", self.anonymous)),
+            "@f" | "@F" => Some(format!("@@{}", self.anonymous + 1)),
+            _ => match (name.strip_prefix('.'), &self.global) {
+                (Some(local), Some(global)) => Some(format!("{}.{}", global, local)),
+                _ => None
+            }
+        }
+    }
+}
+
+// A label or .AT line, index is the number of instructions that came before it
+pub enum MarkKind {
+    Label(String),
+    At
+}
+
+pub struct Mark {
+    pub kind: MarkKind,
+    pub address: u64,
+    pub index: usize,
+    pub file: String,
     pub line: u64
 }
 
-pub fn pars_file(file_path: String) -> (Vec<Instr>, HashMap<String, u64>) {
-    let asm_file = match File::open(file_path) {
+// A .EXPECT line, checked by the test subcommand when the program reaches address.
+// label is the last label before it, the name of the test it belongs to.
+#[derive(Debug, Clone)]
+pub struct Expect {
+    pub text: String,
+    pub address: u64,
+    pub label: Option<String>,
+    pub file: String,
+    pub line: u64,
+    pub column: u64
+}
+
+pub struct SourceLine {
+    pub text: String,
+    pub file: String,
+    pub line: u64,
+    pub column: u64,
+    // the .INCLUDE lines that led to the file, the innermost first
+    pub included_from: Vec<(String, u64)>,
+    // the .DEF values in text, diagnostics point into the line as it was written
+    pub replacements: Vec<Replacement>
+}
+
+impl SourceLine {
+    // an error offset characters into the text
+    pub fn error(&self, offset: u64, message: String) -> Diagnostic {
+        include_notes(Diagnostic::error(&self.file, self.line, self.column(offset), message), &self.included_from)
+    }
+
+    pub fn warning(&self, offset: u64, message: String) -> Diagnostic {
+        include_notes(Diagnostic::warning(&self.file, self.line, self.column(offset), message), &self.included_from)
+    }
+
+    // an error for a span of the text
+    pub fn span_error(&self, span: Span, message: String) -> Diagnostic {
+        let (start, length) = Preprocessor::original_span(&self.replacements, span.start, span.length);
+        include_notes(Diagnostic::error(&self.file, self.line, self.column + start, message).with_length(length), &self.included_from)
+    }
+
+    // the length of the line as it was written
+    pub fn length(&self) -> u64 {
+        Preprocessor::original_span(&self.replacements, 0, self.text.chars().count() as u64).1
+    }
+
+    fn column(&self, offset: u64) -> u64 {
+        self.column + Preprocessor::original_span(&self.replacements, offset, 0).0
+    }
+}
+
+// A note for every .INCLUDE that led to the file of the diagnostic, before the other notes
+// like the include chain of a C compiler
+pub fn include_notes(mut diagnostic: Diagnostic, included_from: &[(String, u64)]) -> Diagnostic {
+    let notes = included_from.iter().map(|(file, line)| format!("included from {}:{}", file, line));
+    diagnostic.notes.splice(0..0, notes);
+    diagnostic
+}
+
+pub struct AsmFile {
+    pub instructions: Vec<Instr>,
+    pub labels: HashMap<String, u64>,
+    // names of numbers that aren't addresses, like the _length of an .INCBIN
+    pub constants: HashMap<String, u64>,
+    pub data: Vec<Data>,
+    pub marks: Vec<Mark>,
+    pub expects: Vec<Expect>,
+    pub settings: Settings,
+    pub warnings: Vec<Diagnostic>
+}
+
+impl AsmFile {
+    // every name an expression can use, labels and constants
+    pub fn symbols(&self) -> HashMap<String, u64> {
+        let mut symbols = self.labels.clone();
+        symbols.extend(self.constants.iter().map(|(n, &v)| (n.clone(), v)));
+        symbols
+    }
+}
+
+pub fn pars_file(file_path: String, settings: Settings) -> Result<AsmFile, Vec<Diagnostic>> {
+    let asm_file = match File::open(&file_path) {
         Ok(f) => f,
-        Err(e) => {
-            println!("{}", e);
-            panic!();
-        }
+        Err(e) => return Err(vec![Diagnostic::error(&file_path, 0, 0, e.to_string())])
     };
 
     let file_data = match read_to_string(asm_file) {
         Ok(f) => f,
-        Err(e) => {
-            println!("{}", e);
-            panic!();
-        }
+        Err(e) => return Err(vec![Diagnostic::error(&file_path, 0, 0, e.to_string())])
     };
 
-    let file_in_lines = file_data.split('\n').collect::<Vec<&str>>();
+    pars_source(file_path, &file_data, settings)
+}
 
-    let file_in_lines = remove_comment(file_in_lines);
-    let file_in_lines = remove_blank(file_in_lines);
+// Like pars_file, for source text that isn't read from disk, like an editor buffer
+pub fn pars_source(file_path: String, file_data: &str, settings: Settings) -> Result<AsmFile, Vec<Diagnostic>> {
+    let mut diagnostics = vec![];
+    let mut stack = vec![];
+    let file_in_lines = read_lines(&file_path, file_data, &[], &settings.include_paths, &mut stack, &mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let preprocessed = Preprocessor::preprocess(file_in_lines, settings)?;
 
     let mut instr = vec![];
     let mut label = HashMap::new();
-    let mut addr_counter = 0;
-    for (line, line_number) in file_in_lines {
-        if line.ends_with(':') {
-            label.insert(line.trim_end_matches(':').to_string(), addr_counter);
-        } else if line.starts_with(".AT") {
-            let new_addr = line.trim_start_matches(".AT").trim();
-            if new_addr.starts_with("0x") {
-                addr_counter = match u64::from_str_radix(new_addr.trim_start_matches("0x"), 16) {
-                    Ok(a) => a,
+    let mut constants = HashMap::new();
+    let mut marks = vec![];
+    let mut expects = vec![];
+    let mut last_label = None;
+    let mut scope = Scope::default();
+    // where every name was defined first, for duplicate label errors
+    let mut defined: HashMap<String, (String, u64)> = HashMap::new();
+    for data in preprocessed.data.iter() {
+        label.insert(data.name.clone(), data.address);
+        defined.insert(data.name.clone(), (data.file.clone(), data.line));
+    }
+    let mut data = preprocessed.data;
+
+    let mut addr_counter = preprocessed.settings.code_segment;
+    // the label right in front of a statement, .INCBIN is named after it
+    let mut in_front = None;
+    for line in preprocessed.lines {
+        let front = in_front.take();
+        let statement = match Syntax::parse_statement(&line.text) {
+            Ok(s) => s,
+            Err(e) => {
+                diagnostics.push(line.span_error(e.span, e.message));
+                continue;
+            }
+        };
+
+        match statement {
+            Statement::Label { name, span } => {
+                let name = match scope.define(&name) {
+                    Ok(n) => n,
                     Err(e) => {
-                        println!("{}", e);
-                        panic!();
+                        diagnostics.push(line.span_error(span, e));
+                        continue;
                     }
                 };
-            } else if new_addr.starts_with("0b") {
-                addr_counter = match u64::from_str_radix(new_addr.trim_start_matches("0b"), 2) {
-                    Ok(a) => a,
+                if let Err(e) = define(&mut defined, &line, &name) {
+                    diagnostics.push(e.with_length(span.length));
+                    continue;
+                }
+                label.insert(name.clone(), addr_counter);
+                if !name.starts_with('@') {
+                    last_label = Some(name.clone());
+                    in_front = Some(name.clone());
+                }
+                marks.push(Mark { kind: MarkKind::Label(name), address: addr_counter, index: instr.len(), file: line.file.clone(), line: line.line });
+            },
+            Statement::Directive { name, arguments, .. } if name == "AT" => {
+                match Preprocessor::pars_value(&arguments) {
+                    Ok(a) => addr_counter = a,
                     Err(e) => {
-                        println!("{}", e);
-                        panic!();
+                        diagnostics.push(line.error(0, e).with_length(line.length()));
+                        continue;
                     }
                 };
-            } else {
-                addr_counter = match u64::from_str_radix(new_addr, 10) {
-                    Ok(a) => a,
+                if addr_counter % 4 != 0 {
+                    diagnostics.push(line.warning(0, format!("address {:#X} isn't aligned to 4 bytes.", addr_counter)));
+                }
+                marks.push(Mark { kind: MarkKind::At, address: addr_counter, index: instr.len(), file: line.file.clone(), line: line.line });
+            },
+            Statement::Directive { name, arguments, .. } if name == "INCBIN" => {
+                let mut symbols = label.clone();
+                symbols.extend(constants.clone());
+                let blob = match pars_incbin(&line, front.clone(), &arguments, &preprocessed.settings.include_paths, addr_counter, &symbols) {
+                    Ok(b) => b,
                     Err(e) => {
-                        println!("{}", e);
-                        panic!();
+                        diagnostics.push(e.with_length(line.length()));
+                        continue;
                     }
                 };
+                // only a named blob has a length constant
+                if front.is_some() {
+                    let length = format!("{}_length", blob.name);
+                    if let Err(e) = define(&mut defined, &line, &length) {
+                        diagnostics.push(e.with_length(line.length()));
+                        continue;
+                    }
+                    constants.insert(length, blob.bytes.len() as u64);
+                }
+
+                // the next instruction stays aligned
+                addr_counter = (addr_counter + blob.bytes.len() as u64).div_ceil(4) * 4;
+                data.push(blob);
+            },
+            Statement::Directive { name, arguments, .. } if name == "EXPECT" => {
+                expects.push(Expect { text: arguments, address: addr_counter, label: last_label.clone(), file: line.file.clone(), line: line.line, column: line.column });
+            },
+            Statement::Directive { span, .. } => {
+                diagnostics.push(line.error(0, format!("Unknown directive: {}", line.text)).with_length(span.length));
+            },
+            statement @ Statement::Instruction { .. } => {
+                instr.push(Instr { data: line.text, statement, address: addr_counter, file: line.file, line: line.line, column: line.column, scope: scope.clone(), included_from: line.included_from, replacements: line.replacements });
+                addr_counter += 4;
             }
-        } else {
-            instr.push(Instr { data: line, address: addr_counter, line: line_number});
-            addr_counter += 4;
         }
     }
 
-    return (instr, label);
+    if diagnostics.iter().any(|d| d.is_error()) {
+        return Err(diagnostics);
+    }
+
+    return Ok(AsmFile { instructions: instr, labels: label, constants, data, marks, expects, settings: preprocessed.settings, warnings: diagnostics });
+}
+
+// A name can only be defined once, data names and labels share the names
+fn define(defined: &mut HashMap<String, (String, u64)>, line: &SourceLine, name: &str) -> Result<(), Diagnostic> {
+    if let Some((file, first)) = defined.get(name) {
+        let place = if *file == line.file { format!("on line {}", first) } else { format!("at {}:{}", file, first) };
+        return Err(line.error(0, format!("Duplicate label '{}'.", name)).with_note(format!("'{}' was first defined {}", name, place)));
+    }
+
+    defined.insert(name.to_string(), (line.file.clone(), line.line));
+    Ok(())
+}
+
+// The bytes of .INCBIN "file" [, offset [, length]] at address, named after the label in
+// front of it, so several parts of one file can be placed. Offset and length are expressions
+// of the labels and constants defined before it.
+fn pars_incbin(line: &SourceLine, label: Option<String>, arguments: &str, include_paths: &[String], address: u64, symbols: &HashMap<String, u64>) -> Result<Data, Diagnostic> {
+    // a blob without a label can't be referred to, the name is only used in error messages
+    let symbol = label.unwrap_or_else(|| String::from(".INCBIN"));
+
+    let shape = || line.error(0, String::from(".INCBIN: expected a file name in quotes, an offset and a length, like .INCBIN \"font.bin\", 0, 0x800."));
+    let (name, rest) = match Syntax::tokenize(arguments).as_deref() {
+        Ok([Token { kind: TokenKind::String(n), span }, ..]) => (n.clone(), arguments.chars().skip((span.start + span.length) as usize).collect::<String>()),
+        _ => return Err(shape())
+    };
+    let rest = rest.trim();
+    let values = match rest.strip_prefix(',') {
+        Some(r) => r.split(',').map(|v| v.trim()).collect::<Vec<&str>>(),
+        None if rest.is_empty() => vec![],
+        None => return Err(shape())
+    };
+    if values.len() > 2 {
+        return Err(shape());
+    }
+
+    let mut numbers = vec![];
+    for (value, what) in values.iter().zip(["offset", "length"]) {
+        match Syntax::parse_expression(value).map_err(|e| e.message).and_then(|e| e.evaluate(symbols)) {
+            Ok(n) => numbers.push(n as u64),
+            Err(e) => return Err(line.error(0, format!(".INCBIN: invalid {} '{}': {}", what, value, e)))
+        }
+    }
+    let offset = numbers.first().copied().unwrap_or(0);
+    let length = numbers.get(1).copied();
+    let name = name.as_str();
+
+    let path = match find_file(&line.file, name, include_paths) {
+        Ok(p) => p,
+        Err(searched) => return Err(line.error(0, format!(".INCBIN: can't find '{}'.", name)).with_note(format!("searched {}", searched.join(", "))))
+    };
+    let bytes = match std::fs::read(&path) {
+        Ok(b) => b,
+        Err(e) => return Err(line.error(0, format!(".INCBIN: can't read '{}': {}", path.display(), e)))
+    };
+
+    let size = bytes.len() as u64;
+    let end = match length {
+        Some(l) => offset.saturating_add(l),
+        None => size.max(offset)
+    };
+    if end > size {
+        return Err(line.error(0, format!(".INCBIN: '{}' has {} bytes, {:#X}..{:#X} is past its end.", name, size, offset, end)));
+    }
+
+    Ok(Data {
+        name: symbol,
+        address,
+        bytes: bytes[offset as usize..end as usize].to_vec(),
+        file: line.file.clone(),
+        line: line.line,
+        column: line.column,
+        length: line.length(),
+        included_from: line.included_from.clone(),
+        code: true
+    })
+}
+
+// The lines of a file without comments and blank lines, with the lines of every .INCLUDE
+// put in its place. stack is the canonical path of every file that is being read.
+fn read_lines(file_path: &str, file_data: &str, included_from: &[(String, u64)], include_paths: &[String], stack: &mut Vec<PathBuf>, diagnostics: &mut Vec<Diagnostic>) -> Vec<SourceLine> {
+    let file_in_lines = file_data.split('\n').collect::<Vec<&str>>();
+    let file_in_lines = remove_comment(file_in_lines);
+    let file_in_lines = remove_blank(file_in_lines, file_path, included_from);
+    let file_in_lines = split_labels(file_in_lines);
+
+    let canonical = Path::new(file_path).canonicalize().unwrap_or_else(|_| PathBuf::from(file_path));
+    stack.push(canonical);
+
+    let mut result = vec![];
+    for line in file_in_lines {
+        let argument = match Syntax::parse_statement(&line.text) {
+            Ok(Statement::Directive { name, arguments, .. }) if name == "INCLUDE" => arguments,
+            _ => {
+                result.push(line);
+                continue;
+            }
+        };
+
+        let include = match Syntax::tokenize(&argument).map(|t| t.into_iter().map(|t| t.kind).collect::<Vec<TokenKind>>()).as_deref() {
+            Ok([TokenKind::String(name)]) => name.clone(),
+            _ => {
+                diagnostics.push(line.error(0, String::from(".INCLUDE: expected a file name in quotes, like .INCLUDE \"file.maasm\".")).with_length(line.text.chars().count() as u64));
+                continue;
+            }
+        };
+
+        let path = match find_file(file_path, &include, include_paths) {
+            Ok(p) => p,
+            Err(searched) => {
+                diagnostics.push(line.error(0, format!(".INCLUDE: can't find '{}'.", include))
+                    .with_length(line.text.chars().count() as u64)
+                    .with_note(format!("searched {}", searched.join(", "))));
+                continue;
+            }
+        };
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if let Some(start) = stack.iter().position(|p| *p == canonical) {
+            let mut chain = stack[start..].iter().map(|p| p.display().to_string()).collect::<Vec<String>>();
+            chain.push(canonical.display().to_string());
+            diagnostics.push(line.error(0, format!(".INCLUDE: '{}' includes itself.", include))
+                .with_length(line.text.chars().count() as u64)
+                .with_note(format!("include cycle {}", chain.join(" -> "))));
+            continue;
+        }
+
+        let path = path.to_string_lossy().to_string();
+        let data = match std::fs::read_to_string(&path) {
+            Ok(d) => d,
+            Err(e) => {
+                diagnostics.push(line.error(0, format!(".INCLUDE: can't read '{}': {}", path, e)).with_length(line.text.chars().count() as u64));
+                continue;
+            }
+        };
+
+        let mut chain = vec![(line.file.clone(), line.line)];
+        chain.extend_from_slice(&line.included_from);
+        result.append(&mut read_lines(&path, &data, &chain, include_paths, stack, diagnostics));
+    }
+
+    stack.pop();
+    result
+}
+
+// A file named in an .INCLUDE or .INCBIN, next to the including file first, then in every
+// include path in order. On failure the places that were searched.
+fn find_file(file_path: &str, include: &str, include_paths: &[String]) -> Result<PathBuf, Vec<String>> {
+    let mut candidates = vec![];
+    if Path::new(include).is_absolute() {
+        candidates.push(PathBuf::from(include));
+    } else {
+        let directory = Path::new(file_path).parent().unwrap_or(Path::new(""));
+        candidates.push(directory.join(include));
+        candidates.extend(include_paths.iter().map(|p| Path::new(p).join(include)));
+    }
+
+    match candidates.iter().find(|c| c.is_file()) {
+        Some(c) => Ok(c.clone()),
+        None => Err(candidates.iter().map(|c| c.display().to_string()).collect())
+    }
 }
 
 fn remove_comment(file_in_lines: Vec<&str>) -> Vec<(String, u64)> {
@@ -82,25 +468,249 @@ fn remove_comment(file_in_lines: Vec<&str>) -> Vec<(String, u64)> {
         if line.starts_with(";") {
             continue;
         } else {
-            let splited_line = line.split(';').collect::<Vec<&str>>();
-            result.push((splited_line[0].to_string(), line_number));
+            result.push((strip_comment(line).to_string(), line_number));
+        }
+    }
+
+    result
+}
+
+// ';' inside a string of a .STR directive doesn't start a comment
+pub fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => ()
+        }
+    }
+
+    line
+}
+
+// "a: b: ADD %a0, %a0, [1]" becomes the lines "a:", "b:" and "ADD %a0, %a0, [1]" with the
+// columns they had, so a label can be in front of any instruction or directive
+fn split_labels(lines: Vec<SourceLine>) -> Vec<SourceLine> {
+    let mut result = vec![];
+    for line in lines {
+        let (labels, rest) = Syntax::split_labels(&line.text);
+        if labels.is_empty() {
+            result.push(line);
+            continue;
+        }
+
+        for (name, span) in labels {
+            result.push(SourceLine { text: format!("{}:", name), file: line.file.clone(), line: line.line, column: line.column + span.start, included_from: line.included_from.clone(), replacements: vec![] });
+        }
+        let text = line.text.chars().skip(rest).collect::<String>();
+        if !text.is_empty() {
+            result.push(SourceLine { text, column: line.column + rest as u64, ..line });
         }
     }
 
     result
 }
 
-fn remove_blank(file_in_lines: Vec<(String, u64)>) -> Vec<(String, u64)> {
+fn remove_blank(file_in_lines: Vec<(String, u64)>, file_path: &str, included_from: &[(String, u64)]) -> Vec<SourceLine> {
     let mut result = vec![];
 
     for (line, line_number) in file_in_lines {
+        let column = line.chars().take_while(|c| c.is_whitespace()).count() as u64 + 1;
         let line = line.trim().to_string();
         if line.is_empty() {
             continue;
         } else {
-            result.push((line.to_string(), line_number));
+            result.push(SourceLine { text: line, file: file_path.to_string(), line: line_number, column, included_from: included_from.to_vec(), replacements: vec![] });
         }
     }
 
     result
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings { code_segment: 0x100, data_segment: 0x80, stack_segment: 0x1000, rom_size: None, include_paths: vec![] }
+    }
+
+    #[test]
+    fn test_labels_in_front() {
+        let source = "start: first:\n  loop: ADD %a0, %a0, [1]\n    JMP loop\nend: .at 0x200\nlast:";
+        let asm_file = pars_source(String::from("t.maasm"), source, settings()).unwrap();
+
+        assert_eq!(asm_file.labels["start"], 0x100);
+        assert_eq!(asm_file.labels["first"], 0x100);
+        assert_eq!(asm_file.labels["loop"], 0x100);
+        assert_eq!(asm_file.labels["end"], 0x108);
+        assert_eq!(asm_file.labels["last"], 0x200);
+        assert_eq!(asm_file.instructions.iter().map(|i| (i.data.as_str(), i.address, i.line, i.column)).collect::<Vec<(&str, u64, u64, u64)>>(),
+            vec![("ADD %a0, %a0, [1]", 0x100, 2, 9), ("JMP loop", 0x104, 3, 5)]);
+    }
+
+    #[test]
+    fn test_instruction_statement() {
+        let asm_file = pars_source(String::from("t.maasm"), ".DEF COUNTER %a0
+.AT 0x10 << 4
+    add COUNTER, COUNTER, [1]
+", settings()).unwrap();
+        let instr = &asm_file.instructions[0];
+        assert_eq!(instr.address, 0x100);
+        match &instr.statement {
+            Statement::Instruction { mnemonic, operands, .. } => {
+                assert_eq!(mnemonic, "add");
+                assert_eq!(operands.iter().map(|o| o.text.as_str()).collect::<Vec<&str>>(), vec!["%a0", "%a0", "[1]"]);
+            },
+            s => panic!("{:?} isn't an instruction", s)
+        }
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let source = ".VAR BYTE count 0\nloop: JMP loop\nloop:\ncount: JMP loop";
+        let errors = pars_source(String::from("t.maasm"), source, settings()).err().unwrap();
+
+        assert_eq!(errors.iter().map(|e| e.to_string()).collect::<Vec<String>>(), vec![
+            Diagnostic::error("t.maasm", 3, 1, String::from("Duplicate label 'loop'.")).with_length(4).with_note(String::from("'loop' was first defined on line 2")).to_string(),
+            Diagnostic::error("t.maasm", 4, 1, String::from("Duplicate label 'count'.")).with_length(5).with_note(String::from("'count' was first defined on line 1")).to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_columns_after_defines() {
+        let source = ".DEF REGISTER %a0\n    ADD REGISTER, REGISTER, [%a9]\n    JMP REGISTER +";
+        let errors = pars_source(String::from("t.maasm"), source, settings()).err().unwrap();
+        assert_eq!(errors.iter().map(|e| (e.line, e.column, e.length)).collect::<Vec<(u64, u64, u64)>>(), vec![(2, 30, 1), (3, 18, 1)]);
+
+        let asm_file = pars_source(String::from("t.maasm"), &source.replace(" +", "").replace("%a9", "0x1FFFF"), settings()).unwrap();
+        let isa = crate::InstructionSet::InstructionSet::load(None).unwrap();
+        let errors = crate::InstructionParser::pars_instructions(asm_file.instructions, asm_file.data, asm_file.labels, &isa).err().unwrap();
+        assert_eq!(errors.iter().map(|e| (e.line, e.column, e.length)).collect::<Vec<(u64, u64, u64)>>(), vec![(2, 29, 9)]);
+    }
+
+    #[test]
+    fn test_local_and_anonymous_labels() {
+        let source = concat!(
+            "first:\n",
+            ".loop: @@: JMP .loop\n",
+            "    JMP @f\n",
+            "@@:\n",
+            "    JMP @b\n",
+            "second:\n",
+            ".loop: JMP first.loop\n"
+        );
+        let asm_file = pars_source(String::from("t.maasm"), source, settings()).unwrap();
+
+        assert_eq!(asm_file.labels["first.loop"], 0x100);
+        assert_eq!(asm_file.labels["@@1"], 0x100);
+        assert_eq!(asm_file.labels["@@2"], 0x108);
+        assert_eq!(asm_file.labels["second.loop"], 0x10C);
+        let resolved = asm_file.instructions.iter().map(|i| (i.scope.resolve(".loop"), i.scope.resolve("@f"), i.scope.resolve("@b"))).collect::<Vec<_>>();
+        assert_eq!(resolved[0], (Some(String::from("first.loop")), Some(String::from("@@2")), Some(String::from("@@1"))));
+        assert_eq!(resolved[2].2, Some(String::from("@@2")));
+        assert_eq!(resolved[3].0, Some(String::from("second.loop")));
+
+        let errors = pars_source(String::from("t.maasm"), ".loop:\n@f:\na.b:\n", settings()).err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.message.as_str()).collect::<Vec<&str>>(), vec![
+            "Local label '.loop' has no global label before it.",
+            "'@f' can't be defined, anonymous labels are defined as '@@:'.",
+            "'a.b' can't be defined, local labels are defined as '.b:' after their global label."
+        ]);
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("mycpuassembler_include_{}", std::process::id()));
+        let drivers = directory.join("drivers");
+        std::fs::create_dir_all(&drivers).unwrap();
+        std::fs::write(drivers.join("uart.maasm"), "uart:\n    JMP uart\n").unwrap();
+        std::fs::write(directory.join("vectors.maasm"), ".INCLUDE \"uart.maasm\"\nvectors: JMP main\n").unwrap();
+        std::fs::write(directory.join("loop.maasm"), "    LOAD8 %a0, [0]\n.include \"main.maasm\"\n").unwrap();
+        std::fs::write(directory.join("broken.maasm"), "    JMP nowhere\n    FOO\n").unwrap();
+
+        let main = directory.join("main.maasm").to_string_lossy().to_string();
+        let settings = Settings { include_paths: vec![drivers.to_string_lossy().to_string()], ..settings() };
+        let asm_file = pars_source(main.clone(), "main:\n.INCLUDE \"vectors.maasm\"\n    JMP main\n", settings.clone()).unwrap();
+        assert_eq!(asm_file.labels["main"], 0x100);
+        assert_eq!(asm_file.labels["uart"], 0x100);
+        assert_eq!(asm_file.labels["vectors"], 0x104);
+        assert_eq!(asm_file.instructions.iter().map(|i| (i.file.rsplit(['/', '\\']).next().unwrap(), i.line, i.included_from.len())).collect::<Vec<(&str, u64, usize)>>(),
+            vec![("uart.maasm", 2, 2), ("vectors.maasm", 2, 1), ("main.maasm", 3, 0)]);
+
+        std::fs::write(&main, ".INCLUDE \"loop.maasm\"\n").unwrap();
+        let errors = pars_file(main.clone(), settings.clone()).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, ".INCLUDE: 'main.maasm' includes itself.");
+        assert!(errors[0].file.ends_with("loop.maasm"));
+        assert_eq!(errors[0].notes[0], format!("included from {}:1", main));
+        assert!(errors[0].notes[1].starts_with("include cycle ") && errors[0].notes[1].ends_with("main.maasm"));
+
+        let errors = pars_source(main.clone(), ".INCLUDE \"missing.maasm\"\n.INCLUDE missing.maasm\n", settings.clone()).err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.message.as_str()).collect::<Vec<&str>>(), vec![
+            ".INCLUDE: can't find 'missing.maasm'.",
+            ".INCLUDE: expected a file name in quotes, like .INCLUDE \"file.maasm\"."
+        ]);
+
+        let asm_file = pars_source(main.clone(), "\n.INCLUDE \"broken.maasm\"\n", settings).unwrap();
+        let isa = crate::InstructionSet::InstructionSet::load(None).unwrap();
+        let errors = crate::InstructionParser::pars_instructions(asm_file.instructions, asm_file.data, asm_file.labels, &isa).err().unwrap();
+        assert!(errors.iter().all(|e| e.file.ends_with("broken.maasm") && e.notes.first() == Some(&format!("included from {}:2", main))));
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<u64>>(), vec![1, 2]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_incbin() {
+        let directory = std::env::temp_dir().join(format!("mycpuassembler_incbin_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("8x8.font"), [1, 2, 3, 4, 5, 6]).unwrap();
+        let main = directory.join("main.maasm").to_string_lossy().to_string();
+
+        // two parts of one file, each named after its label
+        let source = "font: .INCBIN \"8x8.font\", 1, 3\nafter:\n    LOAD32 %a0, font_length\ntail: .INCBIN \"8x8.font\", 4\n";
+        let asm_file = pars_source(main.clone(), source, settings()).unwrap();
+        assert_eq!(asm_file.labels["font"], 0x100);
+        assert_eq!(asm_file.constants["font_length"], 3);
+        assert!(!asm_file.labels.contains_key("font_length"));
+        assert_eq!(asm_file.labels["after"], 0x104);
+        assert_eq!(asm_file.labels["tail"], 0x108);
+        assert_eq!(asm_file.constants["tail_length"], 2);
+        assert_eq!(asm_file.data.iter().map(|d| (d.name.as_str(), d.address, d.bytes.clone())).collect::<Vec<(&str, u64, Vec<u8>)>>(),
+            vec![("font", 0x100, vec![2, 3, 4]), ("tail", 0x108, vec![5, 6])]);
+        let isa = crate::InstructionSet::InstructionSet::load(None).unwrap();
+        let image = crate::InstructionParser::pars_instructions(asm_file.instructions.clone(), vec![], asm_file.symbols(), &isa).unwrap();
+        assert_eq!(image.bytes[0x104], 3);
+
+        // without a label there is no length constant, offset and length can be expressions
+        let source = "first: .INCBIN \"8x8.font\", 0, 3\n.DEF SKIP 2\n.INCBIN \"8x8.font\", SKIP, first_length - 1\n.INCBIN \"8x8.font\", first_length + 1\n";
+        let asm_file = pars_source(main.clone(), source, settings()).unwrap();
+        assert_eq!(asm_file.constants.keys().collect::<Vec<&String>>(), vec!["first_length"]);
+        assert_eq!(asm_file.data.iter().map(|d| (d.name.as_str(), d.address, d.bytes.clone())).collect::<Vec<(&str, u64, Vec<u8>)>>(),
+            vec![("first", 0x100, vec![1, 2, 3]), (".INCBIN", 0x104, vec![3, 4]), (".INCBIN", 0x108, vec![5, 6])]);
+
+        let errors = pars_source(main.clone(), "a: .INCBIN \"8x8.font\", 4, 4\nb: .INCBIN 8x8.font\nc: .INCBIN \"none.bin\"\n.INCBIN \"8x8.font\", later\n.INCBIN \"8x8.font\", 1, 2, 3\n", settings()).err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.message.as_str()).collect::<Vec<&str>>(), vec![
+            ".INCBIN: '8x8.font' has 6 bytes, 0x4..0x8 is past its end.",
+            ".INCBIN: expected a file name in quotes, an offset and a length, like .INCBIN \"font.bin\", 0, 0x800.",
+            ".INCBIN: can't find 'none.bin'.",
+            ".INCBIN: invalid offset 'later': Unknown symbol: later",
+            ".INCBIN: expected a file name in quotes, an offset and a length, like .INCBIN \"font.bin\", 0, 0x800."
+        ]);
+
+        // the bytes of a blob and an instruction at the same address conflict
+        let asm_file = pars_source(main.clone(), "font: .INCBIN \"8x8.font\"\n.AT 0x104\n    JMP [0x100]\n", settings()).unwrap();
+        let errors = crate::InstructionParser::pars_instructions(asm_file.instructions, asm_file.data, asm_file.labels, &isa).err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.to_string()).collect::<Vec<String>>(), vec![
+            Diagnostic::error(&main, 1, 7, String::from("font: Instruction address conflict.")).with_length(19).to_string()
+        ]);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use crate::FileParser::strip_comment;
+use crate::InstructionSet::InstructionSet;
+use crate::Syntax;
+
+const INDENT: &str = "    ";
+// trailing comments start in this column when the code is shorter
+const COMMENT_COLUMN: usize = 40;
+
+// 0x, 0o and 0b in lower case, hex digits in upper case
+fn normalize_number(token: &str) -> String {
+    let lower = token.to_lowercase();
+    for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+        if let Some(digits) = lower.strip_prefix(prefix) {
+            if !digits.is_empty() && digits.chars().all(|c| c == '_' || c.is_digit(radix)) {
+                return format!("{}{}", prefix, digits.to_uppercase());
+            }
+        }
+    }
+
+    token.to_string()
+}
+
+// Runs of spaces become one, numbers are normalized and registers are lower case.
+// Strings are left alone.
+fn normalize_text(text: &str) -> String {
+    let mut result = String::new();
+    let chars = text.trim().chars().collect::<Vec<char>>();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        if c == '"' {
+            let start = index;
+            index += 1;
+            while index < chars.len() && chars[index] != '"' {
+                if chars[index] == '\\' {
+                    index += 1;
+                }
+                index += 1;
+            }
+            index = (index + 1).min(chars.len());
+            result.extend(&chars[start..index]);
+        } else if c.is_whitespace() {
+            while index < chars.len() && chars[index].is_whitespace() {
+                index += 1;
+            }
+            result.push(' ');
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '%' {
+            let start = index;
+            index += 1;
+            while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '_') {
+                index += 1;
+            }
+            let token = chars[start..index].iter().collect::<String>();
+            if token.starts_with('%') {
+                result += &token.to_lowercase();
+            } else if c.is_ascii_digit() {
+                result += &normalize_number(&token);
+            } else {
+                result += &token;
+            }
+        } else {
+            result.push(c);
+            index += 1;
+        }
+    }
+
+    result
+}
+
+// MNEMONIC and operands separated by ", ", the operands start in one column
+fn format_instruction(code: &str, isa: &InstructionSet, width: usize) -> String {
+    let (mnemonic, operands) = match code.split_once(char::is_whitespace) {
+        Some((m, o)) => (m, o.trim()),
+        None => (code, "")
+    };
+    let mnemonic = match isa.instructions().iter().find(|i| i.name.eq_ignore_ascii_case(mnemonic)) {
+        Some(i) => i.name.clone(),
+        None => mnemonic.to_string()
+    };
+    if operands.is_empty() {
+        return format!("{}{}", INDENT, mnemonic);
+    }
+
+    let operands = operands.split(',').map(normalize_text).collect::<Vec<String>>().join(", ");
+    format!("{}{:<width$} {}", INDENT, mnemonic, operands, width = width)
+}
+
+// Labels and directives start at column 0, instructions and .EXPECT lines are indented
+// once, a label in front of an instruction gets a line of its own. Comments are kept,
+// a comment on its own line stays at column 0 if it was there.
+// Blank lines are kept, but never more than one in a row.
+pub fn format(source: &str, isa: &InstructionSet) -> String {
+    let width = isa.instructions().iter().map(|i| i.name.len()).max().unwrap_or(0);
+    let mut lines: Vec<String> = vec![];
+    for line in source.split('\n') {
+        let line = line.trim_end();
+        let code = strip_comment(line);
+        let comment = Some(&line[code.len()..]).filter(|c| !c.is_empty());
+        let code = code.trim();
+
+        if code.is_empty() {
+            let formatted = match comment {
+                Some(c) if line.starts_with(';') => c.to_string(),
+                Some(c) => format!("{}{}", INDENT, c),
+                None => String::new()
+            };
+            if formatted.is_empty() && lines.last().is_none_or(|l| l.is_empty()) {
+                continue;
+            }
+            lines.push(formatted);
+            continue;
+        }
+
+        // labels in front of an instruction or directive get their own lines
+        let (labels, rest) = Syntax::split_labels(code);
+        let mut codes = labels.into_iter().map(|(name, _)| format!("{}:", name)).collect::<Vec<String>>();
+        let rest = code.chars().skip(rest).collect::<String>();
+        if matches!(Syntax::parse_statement(&rest), Ok(Syntax::Statement::Directive { name, .. }) if name == "EXPECT") {
+            codes.push(format!("{}{}", INDENT, normalize_text(&rest)));
+        } else if rest.starts_with('.') {
+            codes.push(normalize_text(&rest));
+        } else if !rest.is_empty() {
+            codes.push(format_instruction(&rest, isa, width));
+        }
+
+        let last = codes.pop().unwrap_or_default();
+        lines.extend(codes);
+        lines.push(match comment {
+            Some(c) if last.len() < COMMENT_COLUMN => format!("{:<width$}{}", last, c, width = COMMENT_COLUMN),
+            Some(c) => format!("{} {}", last, c),
+            None => last
+        });
+    }
+
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    let mut result = lines.join("\n");
+    result.push('\n');
+    result
+}
+
+// The number of the first line that format would change, None if the source is formatted
+pub fn first_difference(source: &str, formatted: &str) -> Option<usize> {
+    if source == formatted {
+        return None;
+    }
+
+    let mut source_lines = source.split('\n');
+    let mut formatted_lines = formatted.split('\n');
+    let mut number = 1;
+    loop {
+        match (source_lines.next(), formatted_lines.next()) {
+            (Some(a), Some(b)) if a == b => number += 1,
+            _ => return Some(number)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize_number("0XffA0"), "0xFFA0");
+        assert_eq!(normalize_number("0B1010_0000"), "0b1010_0000");
+        assert_eq!(normalize_number("0xZZ"), "0xZZ");
+        assert_eq!(normalize_number("10"), "10");
+        assert_eq!(normalize_text("%A0,   [0xff]"), "%a0, [0xFF]");
+        assert_eq!(normalize_text(".STR  msg   \"0xff  ;  %A0\""), ".STR msg \"0xff  ;  %A0\"");
+        assert_eq!(normalize_text("(loop_0xab & 0xffff0000) >> 16"), "(loop_0xab & 0xFFFF0000) >> 16");
+    }
+
+    #[test]
+    fn test_format() {
+        let isa = InstructionSet::load(None).unwrap();
+        let source = concat!(
+            "; header\n",
+            ".AT   0xf0000\n",
+            "\n\n",
+            "start:\n",
+            "  load8 %A0,[0x0]  ; first\n",
+            "\tcheck:  next :LOAD8 %a1, [1] ; both\n",
+            "        LOAD8  %a3, %a0\n",
+            "        ;\n",
+            "        STORE16 %a1,%a0,%a2\n",
+            ".EXPECT  %A0 == 0xff\n",
+            ".expect %A1 == 1\n",
+            "    FOO bar\n",
+            ".STR msg \"a\\\";b\" ; quote\n",
+            "\n"
+        );
+        let expected = concat!(
+            "; header\n",
+            ".AT 0xF0000\n",
+            "\n",
+            "start:\n",
+            "    LOAD8   %a0, [0x0]                  ; first\n",
+            "check:\n",
+            "next:\n",
+            "    LOAD8   %a1, [1]                    ; both\n",
+            "    LOAD8   %a3, %a0\n",
+            "    ;\n",
+            "    STORE16 %a1, %a0, %a2\n",
+            "    .EXPECT %a0 == 0xFF\n",
+            "    .expect %a1 == 1\n",
+            "    FOO     bar\n",
+            ".STR msg \"a\\\";b\"                        ; quote\n"
+        );
+
+        let formatted = format(source, &isa);
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted, &isa), formatted);
+        assert_eq!(first_difference(source, &formatted), Some(2));
+        assert_eq!(first_difference(&formatted, &formatted), None);
+    }
+}
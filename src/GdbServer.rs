@@ -0,0 +1,539 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::InstructionParser::get_register_name;
+use crate::Simulator::{AccessKind, Machine, PC, ZERO};
+
+// Stop signals reported to gdb
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// How many instructions continue runs between two looks for a ctrl-c from gdb
+const INTERRUPT_CHECK: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access
+}
+
+impl WatchKind {
+    fn name(&self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch"
+        }
+    }
+}
+
+// gdb numbers the registers that have a name one after the other, in label order
+pub fn register_labels() -> Vec<usize> {
+    (0..64).filter(|&l| get_register_name(l as u8).is_some()).collect()
+}
+
+pub fn target_xml() -> String {
+    let mut result = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.macpu.core\">\n");
+    for (number, label) in register_labels().into_iter().enumerate() {
+        let name = get_register_name(label as u8).unwrap_or_default().to_lowercase();
+        let kind = if label == PC { "code_ptr" } else { "uint32" };
+        result += &format!("    <reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"{}\"/>\n", name, number, kind);
+    }
+    result += "  </feature>\n</target>\n";
+
+    result
+}
+
+// $data#checksum, with the characters the protocol reserves escaped
+pub fn frame(data: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for &byte in data.as_bytes() {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => body.extend([b'}', byte ^ 0x20]),
+            _ => body.push(byte)
+        }
+    }
+
+    let checksum = body.iter().fold(0_u8, |s, &b| s.wrapping_add(b));
+    let mut result = vec![b'$'];
+    result.append(&mut body);
+    result.extend(format!("#{:02x}", checksum).into_bytes());
+    result
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn pars_hex(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 16).map_err(|_| format!("'{}' isn't a hex number.", text))
+}
+
+fn pars_hex_bytes(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(format!("'{}' isn't a list of bytes.", text));
+    }
+
+    (0..text.len()).step_by(2).map(|i| pars_hex(&text[i..i + 2]).map(|b| b as u8)).collect()
+}
+
+// ADDR,LENGTH as sent by m, M, Z and z
+fn pars_range(text: &str) -> Result<(u64, u64), String> {
+    match text.split_once(',') {
+        Some((address, length)) => Ok((pars_hex(address)?, pars_hex(length)?)),
+        None => Err(format!("'{}' isn't an address and length.", text))
+    }
+}
+
+// What gdb asked for and what the stub answers, without the network around it
+pub struct Target<'a> {
+    pub machine: Machine<'a>,
+    breakpoints: Vec<u64>,
+    watchpoints: Vec<(WatchKind, u64, u64)>,
+    max_cycles: u64
+}
+
+// None closes the connection
+pub type Reply = Option<String>;
+
+impl<'a> Target<'a> {
+    pub fn new(machine: Machine<'a>, max_cycles: u64) -> Target<'a> {
+        Target { machine, breakpoints: vec![], watchpoints: vec![], max_cycles }
+    }
+
+    // interrupted is asked now and then while continue runs, true stops it
+    pub fn packet(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let result = match packet {
+            "?" => Ok(format!("S{:02x}", SIGTRAP)),
+            "g" => Ok(self.read_registers()),
+            "s" => Ok(self.step()),
+            "c" => Ok(self.resume(interrupted)),
+            "D" => return Some(String::from("OK")),
+            "k" => return None,
+            "qAttached" => Ok(String::from("1")),
+            "qC" => Ok(String::from("QC1")),
+            "qfThreadInfo" => Ok(String::from("m1")),
+            "qsThreadInfo" => Ok(String::from("l")),
+            "QStartNoAckMode" => Ok(String::from("OK")),
+            _ if packet.starts_with("qSupported") => Ok(String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+")),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => self.read_features(&packet["qXfer:features:read:target.xml:".len()..]),
+            _ if packet.starts_with('H') => Ok(String::from("OK")),
+            _ if packet.starts_with('G') => self.write_registers(&packet[1..]),
+            _ if packet.starts_with('p') => self.read_register(&packet[1..]),
+            _ if packet.starts_with('P') => self.write_register(&packet[1..]),
+            _ if packet.starts_with('m') => self.read_memory(&packet[1..]),
+            _ if packet.starts_with('M') => self.write_memory(&packet[1..]),
+            _ if packet.starts_with('Z') => self.set_point(&packet[1..], true),
+            _ if packet.starts_with('z') => self.set_point(&packet[1..], false),
+            // an empty reply tells gdb the packet isn't supported
+            _ => Ok(String::new())
+        };
+
+        match result {
+            Ok(r) => Some(r),
+            Err(_) => Some(String::from("E01"))
+        }
+    }
+
+    fn read_features(&self, range: &str) -> Result<String, String> {
+        let (offset, length) = pars_range(range)?;
+        let xml = target_xml();
+        let start = (offset as usize).min(xml.len());
+        let end = (start + length as usize).min(xml.len());
+        let more = if end < xml.len() { 'm' } else { 'l' };
+
+        Ok(format!("{}{}", more, &xml[start..end]))
+    }
+
+    fn register_value(&self, label: usize) -> u32 {
+        if label == ZERO { 0 } else { self.machine.registers[label] }
+    }
+
+    fn set_register_value(&mut self, label: usize, value: u32) {
+        if label != ZERO {
+            self.machine.registers[label] = value;
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        register_labels().into_iter().map(|l| hex_bytes(&self.register_value(l).to_le_bytes())).collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> Result<String, String> {
+        let bytes = pars_hex_bytes(data)?;
+        let labels = register_labels();
+        if bytes.len() != labels.len() * 4 {
+            return Err(format!("Expected {} register bytes, got {}.", labels.len() * 4, bytes.len()));
+        }
+
+        for (label, value) in labels.into_iter().zip(bytes.chunks(4)) {
+            self.set_register_value(label, u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+        }
+        Ok(String::from("OK"))
+    }
+
+    fn label(&self, number: &str) -> Result<usize, String> {
+        let number = pars_hex(number)?;
+        match register_labels().get(number as usize) {
+            Some(&l) => Ok(l),
+            None => Err(format!("There is no register {}.", number))
+        }
+    }
+
+    fn read_register(&self, number: &str) -> Result<String, String> {
+        let label = self.label(number)?;
+        Ok(hex_bytes(&self.register_value(label).to_le_bytes()))
+    }
+
+    fn write_register(&mut self, data: &str) -> Result<String, String> {
+        let (number, value) = match data.split_once('=') {
+            Some(p) => p,
+            None => return Err(format!("'{}' isn't a register and value.", data))
+        };
+        let label = self.label(number)?;
+        let value = pars_hex_bytes(value)?;
+        if value.len() != 4 {
+            return Err(format!("A register is 4 bytes, not {}.", value.len()));
+        }
+
+        self.set_register_value(label, u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+        Ok(String::from("OK"))
+    }
+
+    // stops at the first unmapped byte, gdb accepts a shorter answer
+    fn read_memory(&self, range: &str) -> Result<String, String> {
+        let (address, length) = pars_range(range)?;
+        let mut bytes = vec![];
+        for index in 0..length {
+            match self.machine.memory.read_byte(address + index) {
+                Ok(b) => bytes.push(b),
+                Err(_) => break
+            }
+        }
+        if bytes.is_empty() && length > 0 {
+            return Err(format!("Nothing mapped at {:#X}.", address));
+        }
+
+        Ok(hex_bytes(&bytes))
+    }
+
+    // like a programmer, gdb can write the ROM too
+    fn write_memory(&mut self, data: &str) -> Result<String, String> {
+        let (range, bytes) = match data.split_once(':') {
+            Some(p) => p,
+            None => return Err(format!("'{}' has no data.", data))
+        };
+        let (address, length) = pars_range(range)?;
+        let bytes = pars_hex_bytes(bytes)?;
+        if bytes.len() as u64 != length {
+            return Err(format!("Expected {} bytes, got {}.", length, bytes.len()));
+        }
+
+        for index in 0..length {
+            self.machine.memory.read_byte(address + index)?;
+        }
+        self.machine.memory.load(address, &bytes)?;
+        Ok(String::from("OK"))
+    }
+
+    // TYPE,ADDR,KIND: 0 and 1 are breakpoints, 2 to 4 watch writes, reads or both
+    fn set_point(&mut self, data: &str, insert: bool) -> Result<String, String> {
+        let (kind, range) = match data.split_once(',') {
+            Some(p) => p,
+            None => return Err(format!("'{}' isn't a breakpoint.", data))
+        };
+        let (address, length) = pars_range(range)?;
+
+        let watch = match kind {
+            "0" | "1" => {
+                self.breakpoints.retain(|&a| a != address);
+                if insert {
+                    self.breakpoints.push(address);
+                }
+                return Ok(String::from("OK"));
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Ok(String::new())
+        };
+
+        self.watchpoints.retain(|&w| w != (watch, address, length));
+        if insert {
+            self.watchpoints.push((watch, address, length));
+        }
+        Ok(String::from("OK"))
+    }
+
+    // One instruction, Some is the stop reply when it has to stop
+    fn single_step(&mut self) -> Option<String> {
+        let step = match self.machine.step() {
+            Ok(s) => s,
+            Err(_) => return Some(format!("S{:02x}", SIGSEGV))
+        };
+
+        for &(kind, address, length) in self.watchpoints.iter() {
+            for access in step.accesses.iter() {
+                let matches = match access.kind {
+                    AccessKind::Fetch => false,
+                    AccessKind::Read => kind != WatchKind::Write,
+                    AccessKind::Write => kind != WatchKind::Read
+                };
+                if matches && access.address < address + length && address < access.address + access.size as u64 {
+                    return Some(format!("T{:02x}{}:{:x};", SIGTRAP, kind.name(), address));
+                }
+            }
+        }
+
+        // a jump to the instruction itself ends the program
+        if self.machine.pc() == step.address {
+            return Some(String::from("W00"));
+        }
+        None
+    }
+
+    fn step(&mut self) -> String {
+        self.single_step().unwrap_or(format!("S{:02x}", SIGTRAP))
+    }
+
+    fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let limit = self.machine.cycles + self.max_cycles;
+        let mut count = 0_u64;
+        loop {
+            if let Some(reply) = self.single_step() {
+                return reply;
+            }
+            if self.breakpoints.contains(&self.machine.pc()) {
+                return format!("T{:02x}swbreak:;", SIGTRAP);
+            }
+            if self.machine.cycles >= limit {
+                return format!("S{:02x}", SIGTRAP);
+            }
+
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK) && interrupted() {
+                return format!("S{:02x}", SIGINT);
+            }
+        }
+    }
+}
+
+// The packets of one connection, acknowledged until gdb turns that off
+struct Connection {
+    stream: TcpStream,
+    buffer: VecDeque<u8>,
+    acknowledge: bool
+}
+
+impl Connection {
+    fn fill(&mut self) -> std::io::Result<bool> {
+        let mut data = [0; 4096];
+        let count = self.stream.read(&mut data)?;
+        self.buffer.extend(&data[..count]);
+        Ok(count > 0)
+    }
+
+    fn next_byte(&mut self) -> std::io::Result<Option<u8>> {
+        while self.buffer.is_empty() {
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.buffer.pop_front())
+    }
+
+    // None when gdb closed the connection
+    fn read_packet(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            // acknowledgements and stray ctrl-c's between packets are dropped
+            match self.next_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None)
+            }
+
+            let mut body = vec![];
+            loop {
+                match self.next_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => body.push(b),
+                    None => return Ok(None)
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.next_byte()? {
+                    Some(b) => *digit = b,
+                    None => return Ok(None)
+                }
+            }
+
+            let expected = body.iter().fold(0_u8, |s, &b| s.wrapping_add(b));
+            let valid = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16) == Ok(expected);
+            if self.acknowledge {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || !self.acknowledge {
+                return Ok(Some(String::from_utf8_lossy(&body).to_string()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        self.stream.write_all(&frame(data))?;
+        if self.acknowledge {
+            // the answer is one byte, resending on '-' is left to a better network
+            while let Some(b) = self.next_byte()? {
+                if b == b'+' || b == b'-' {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // true when a ctrl-c is waiting, without blocking
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let filled = self.fill();
+        let _ = self.stream.set_nonblocking(false);
+        if let Err(e) = filled {
+            if e.kind() != ErrorKind::WouldBlock {
+                return false;
+            }
+        }
+
+        match self.buffer.iter().position(|&b| b == 0x03) {
+            Some(index) => {
+                self.buffer.remove(index);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+// Waits for one gdb on the port of localhost and serves it until it detaches
+pub fn serve(target: &mut Target, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    println!("waiting for gdb on 127.0.0.1:{}", port);
+    let (stream, address) = listener.accept().map_err(|e| e.to_string())?;
+    println!("gdb connected from {}", address);
+    let _ = stream.set_nodelay(true);
+
+    let mut connection = Connection { stream, buffer: VecDeque::new(), acknowledge: true };
+    loop {
+        let packet = match connection.read_packet().map_err(|e| e.to_string())? {
+            Some(p) => p,
+            None => return Ok(())
+        };
+
+        let reply = {
+            let mut interrupted = || connection.interrupted();
+            target.packet(&packet, &mut interrupted)
+        };
+        let reply = match reply {
+            Some(r) => r,
+            None => return Ok(())
+        };
+
+        connection.send(&reply).map_err(|e| e.to_string())?;
+        if packet == "QStartNoAckMode" {
+            connection.acknowledge = false;
+        }
+        if packet == "D" || reply.starts_with('W') {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::InstructionParser::encode_instruction;
+    use crate::InstructionSet::InstructionSet;
+    use crate::Simulator::{Memory, Region};
+
+    fn target(isa: &InstructionSet) -> Target<'_> {
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x800, true), Region::new("rom", 0x800, 0x100, false)]).unwrap();
+        let program = ["LOAD8 %a0, [0x40]", "LOAD8 %a1, [7]", "STORE8 %a1, %a0", "ADD %a1, %a1, [1]", "JMP [0x808]"];
+        for (index, text) in program.iter().enumerate() {
+            let (mnemonic, operands) = text.split_once(' ').unwrap();
+            let word = encode_instruction(isa.get(mnemonic).unwrap(), operands.split(", ").collect(), HashMap::new()).unwrap();
+            memory.load(0x800 + index as u64 * 4, &word.to_le_bytes()).unwrap();
+        }
+
+        Target::new(Machine::new(isa, memory, 0x800), 1_000_000)
+    }
+
+    fn number(name: &str) -> usize {
+        register_labels().iter().position(|&l| get_register_name(l as u8) == Some(name)).unwrap()
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame("OK"), b"$OK#9a".to_vec());
+        assert_eq!(frame("a#b"), b"$a}\x03b#43".to_vec());
+    }
+
+    #[test]
+    fn test_target_xml() {
+        let xml = target_xml();
+        assert!(xml.contains("<reg name=\"zero\" bitsize=\"32\" regnum=\"0\" type=\"uint32\"/>"));
+        assert!(xml.contains(&format!("<reg name=\"pc\" bitsize=\"32\" regnum=\"{}\" type=\"code_ptr\"/>", number("PC"))));
+        assert_eq!(xml.matches("<reg ").count(), register_labels().len());
+    }
+
+    #[test]
+    fn test_packets() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut target = target(&isa);
+        let mut never = || false;
+        let mut send = |target: &mut Target, packet: &str| target.packet(packet, &mut never).unwrap();
+
+        assert_eq!(send(&mut target, "?"), "S05");
+        assert_eq!(send(&mut target, &format!("p{:x}", number("PC"))), "00080000");
+        assert_eq!(send(&mut target, "m800,4"), hex_bytes(&target.machine.memory.read(0x800, 4).unwrap().to_le_bytes()));
+        assert_eq!(send(&mut target, "m8fe,4"), "0000");
+        assert_eq!(send(&mut target, "m900,4"), "E01");
+        assert!(send(&mut target, "qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+        assert_eq!(send(&mut target, "Z0,808,4"), "OK");
+        assert_eq!(send(&mut target, "c"), "T05swbreak:;");
+        assert_eq!(send(&mut target, &format!("p{:x}", number("A1"))), "07000000");
+        assert_eq!(send(&mut target, "Z2,40,1"), "OK");
+        assert_eq!(send(&mut target, "c"), "T05watch:40;");
+        assert_eq!(send(&mut target, "m40,1"), "07");
+        assert_eq!(send(&mut target, "z2,40,1"), "OK");
+        assert_eq!(send(&mut target, "s"), "S05");
+        assert_eq!(send(&mut target, &format!("P{:x}=20000000", number("A1"))), "OK");
+        assert_eq!(target.machine.registers[register_labels()[number("A1")]], 0x20);
+        assert_eq!(send(&mut target, "M900,2:abcd"), "E01");
+        assert_eq!(send(&mut target, "M804,1:ff"), "OK");
+        assert_eq!(send(&mut target, "vMustReplyEmpty"), "");
+        assert_eq!(target.packet("k", &mut never), None);
+    }
+
+    #[test]
+    fn test_halt_and_interrupt() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut target = target(&isa);
+        // the loop never ends, so only the interrupt stops it
+        let mut interrupted = || true;
+        assert_eq!(target.packet("c", &mut interrupted), Some(String::from("S02")));
+
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x100, true)]).unwrap();
+        let jump = encode_instruction(isa.get("JMP").unwrap(), vec!["[0x10]"], HashMap::new()).unwrap();
+        memory.load(0x10, &jump.to_le_bytes()).unwrap();
+        let mut target = Target::new(Machine::new(&isa, memory, 0x10), 1000);
+        let mut never = || false;
+        assert_eq!(target.packet("c", &mut never), Some(String::from("W00")));
+    }
+}
@@ -1,230 +1,244 @@
-use std::{collections::HashMap, num::ParseIntError};
-use crate::FileParser::Instr;
+use std::{cmp::Reverse, collections::HashMap};
+use crate::Diagnostic::Diagnostic;
+use crate::FileParser::{include_notes, Instr};
+use crate::Preprocessor::Data;
+use crate::InstructionSet::{Constraint, FormKind, Instruction, InstructionSet};
+use crate::Syntax::{self, Operand, OperandKind, Statement};
+
+pub const OPCODE_SHIFT: u32 = 22;
+
+pub fn pars_instructions(instructions: Vec<Instr>, data: Vec<Data>, labels: HashMap<String, u64>, isa: &InstructionSet) -> Result<Image, Vec<Diagnostic>> {
+    let mut result = Image::new();
+    let mut diagnostics = vec![];
+    for line in instructions {
+        let bin = match pars_line(&line, &labels, isa) {
+            Ok(b) => b,
+            Err(d) => {
+                diagnostics.push(include_notes(d, &line.included_from));
+                continue;
+            }
+        };
 
-#[derive(Debug, Clone)]
-struct Constraint {
-    target_invalid_reg: Vec<String>,
-    source_0_invalid_reg: Vec<String>,
-    source_1_invalid_reg: Vec<String>,
-    immediate_0_number_max: u32,
-    immediate_1_number_max: u32
+        match result.write(line.address, &bin.to_le_bytes()) {
+            Ok(()) => result.code.push((line.address, line.address + 4)),
+            Err(e) => {
+                let (column, length) = line.span(0, line.data.chars().count() as u64);
+                let diagnostic = Diagnostic::error(&line.file, line.line, column, e).with_length(length);
+                diagnostics.push(include_notes(diagnostic, &line.included_from));
+            }
+        }
+    }
+
+    for item in data {
+        match result.write(item.address, &item.bytes) {
+            Ok(()) if item.code => result.code.push((item.address, item.address + item.bytes.len() as u64)),
+            Ok(()) => (),
+            Err(e) => {
+                let diagnostic = Diagnostic::error(&item.file, item.line, item.column, format!("{}: {}", item.name, e)).with_length(item.length);
+                diagnostics.push(include_notes(diagnostic, &item.included_from));
+            }
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    result.merge_segments();
+    Ok(result)
 }
 
-pub fn pars_instructions(instructions: Vec<Instr>, labels: HashMap<String, u64>) -> Vec<u8> {
-    let mut result = vec![];
-    for line in instructions {
-        let mut bin = 0;
-
-        if line.data.starts_with("LOAD8") || line.data.starts_with("load8") {
-            bin = match InstPars::pars_load8(line.data.trim_start_matches("LOAD8").trim_start_matches("load8").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("LOAD16") || line.data.starts_with("load16") {
-            bin = match InstPars::pars_load16(line.data.trim_start_matches("LOAD16").trim_start_matches("load16").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("LOAD32") || line.data.starts_with("load32") {
-            bin = match InstPars::pars_load32(line.data.trim_start_matches("LOAD32").trim_start_matches("load32").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("STORE8") || line.data.starts_with("store8") {
-            bin = match InstPars::pars_store8(line.data.trim_start_matches("STORE8").trim_start_matches("store8").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("STORE16") || line.data.starts_with("store16") {
-            bin = match InstPars::pars_store16(line.data.trim_start_matches("STORE16").trim_start_matches("store16").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("STORE32") || line.data.starts_with("store32") {
-            bin = match InstPars::pars_store32(line.data.trim_start_matches("STORE32").trim_start_matches("store32").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("MOVE") || line.data.starts_with("move") {
-            bin = match InstPars::pars_move(line.data.trim_start_matches("MOVE").trim_start_matches("move").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("ADD") || line.data.starts_with("add") {
-            bin = match InstPars::pars_add(line.data.trim_start_matches("ADD").trim_start_matches("add").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("SUB") || line.data.starts_with("sub") {
-            bin = match InstPars::pars_sub(line.data.trim_start_matches("SUB").trim_start_matches("sub").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("EQ") || line.data.starts_with("eq") {
-            bin = match InstPars::pars_eq(line.data.trim_start_matches("EQ").trim_start_matches("eq").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("JMP") || line.data.starts_with("jmp") {
-            bin = match InstPars::pars_jmp(line.data.trim_start_matches("JMP").trim_start_matches("jmp").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("OJMP") || line.data.starts_with("ojmp") {
-            bin = match InstPars::pars_ojmp(line.data.trim_start_matches("OJMP").trim_start_matches("ojmp").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
-        } else if line.data.starts_with("ZJMP") || line.data.starts_with("zjmp") {
-            bin = match InstPars::pars_zjmp(line.data.trim_start_matches("ZJMP").trim_start_matches("zjmp").trim().split(',').map(|x| x.trim()).collect::<Vec<&str>>(), labels.clone()) {
-                Ok(c) => c,
-                Err(e) => {
-                    println!("{}", e);
-                    println!("line: {}", line.line);
-                    panic!();
-                }
-            };
+fn pars_line(line: &Instr, labels: &HashMap<String, u64>, isa: &InstructionSet) -> Result<u32, Diagnostic> {
+    let (mnemonic, span, mut operands) = match &line.statement {
+        Statement::Instruction { mnemonic, span, operands } => (mnemonic, *span, operands.clone()),
+        _ => {
+            let (column, length) = line.span(0, line.data.chars().count() as u64);
+            return Err(Diagnostic::error(&line.file, line.line, column, format!("Expected an instruction, found '{}'.", line.data)).with_length(length));
         }
+    };
 
-        if result.len() < line.address as usize {
-            let diff = line.address as usize - result.len();
-            let mut zeros = vec![0_u8; diff];
-            result.append(&mut zeros);
-            result.append(&mut bin.to_le_bytes().to_vec());
-        } else if result.len() == line.address as usize {
-            result.append(&mut bin.to_le_bytes().to_vec());
-        } else if result.len() > line.address as usize {
-            let data = bin.to_le_bytes();
-            for addr in line.address..line.address + 4 {
-                if result[addr as usize] == 0 {
-                    result[addr as usize] = data[(addr - line.address) as usize];
-                } else {
-                    println!("Instruction address conflict.");
-                    panic!();
-                }
-            }
+    // .loop, @f and @b become the labels they stand for here
+    for operand in operands.iter_mut() {
+        operand.rename(&|name| line.scope.resolve(name).filter(|n| labels.contains_key(n)));
+    }
+
+    let inst = match isa.get(mnemonic) {
+        Some(i) => i,
+        None => {
+            let (column, length) = line.span(span.start, span.length);
+            return Err(Diagnostic::error(&line.file, line.line, column, format!("Unknown instruction '{}'.", mnemonic))
+                .with_length(length)
+                .with_note(format!("known instructions are {}", isa.mnemonics().join(", "))));
+        }
+    };
+
+    match pars_operands(inst, &operands, labels) {
+        Ok(c) => Ok(c),
+        Err(errors) => Err(operand_diagnostic(line, &operands, errors))
+    }
+}
+
+// The form that got furthest through the operands is reported, preferring a violated constraint
+// over an operand of the wrong kind. The errors of the other forms that were tried become notes.
+fn operand_diagnostic(line: &Instr, operands: &[Operand], mut errors: Vec<(Option<FormKind>, OperandError)>) -> Diagnostic {
+    errors.sort_by_key(|(_, e)| (Reverse(e.operand), e.note.is_none()));
+
+    let mut errors = errors.into_iter();
+    let (_, first) = errors.next().expect("an instruction error without a reason");
+
+    let (column, length) = match first.operand.and_then(|i| operands.get(i)) {
+        Some(operand) => line.span(operand.span.start, operand.span.length),
+        None => line.span(0, line.data.chars().count() as u64)
+    };
+
+    let mut diagnostic = Diagnostic::error(&line.file, line.line, column, first.message).with_length(length);
+    if let Some(note) = first.note {
+        diagnostic = diagnostic.with_note(note);
+    }
+    for (kind, error) in errors {
+        if error.message == diagnostic.message {
+            continue;
+        }
+        match kind {
+            Some(k) => diagnostic = diagnostic.with_note(format!("as a '{}' form: {}", k.name(), error.message)),
+            None => diagnostic = diagnostic.with_note(error.message)
         }
     }
 
-    result
+    diagnostic
 }
 
-fn get_register_label(register_name: &str) -> Result<u8, String> {
-    return match register_name {
-        "PC" | "pc" => Ok(0b101001),
-        "ZERO" | "zero" => Ok(0b000000),
-
-        "A0" | "a0" => Ok(1),
-        "A1" | "a1" => Ok(2),
-        "A2" | "a2" => Ok(3),
-        "A3" | "a3" => Ok(4),
-        "AR0" | "ar0" => Ok(5),
-        "AR1" | "ar1" => Ok(6),
-        "AR2" | "ar2" => Ok(7),
-        "ASS" | "ass" => Ok(8),
-        "ASP" | "asp" => Ok(9),
-        "ADS" | "ads" => Ok(10),
-
-        "B0" | "b0" => Ok(11),
-        "B1" | "b1" => Ok(12),
-        "B2" | "b2" => Ok(13),
-        "B3" | "b3" => Ok(14),
-        "BR0" | "br0" => Ok(15),
-        "BR1" | "br1" => Ok(16),
-        "BR2" | "br2" => Ok(17),
-        "BSS" | "bss" => Ok(18),
-        "BSP" | "bsp" => Ok(19),
-        "BDS" | "bds" => Ok(20),
-
-        "C0" | "c0" => Ok(21),
-        "C1" | "c1" => Ok(22),
-        "C2" | "c2" => Ok(23),
-        "C3" | "c3" => Ok(24),
-        "CR0" | "cr0" => Ok(25),
-        "CR1" | "cr1" => Ok(26),
-        "CR2" | "cr2" => Ok(27),
-        "CSS" | "css" => Ok(28),
-        "CSP" | "csp" => Ok(29),
-        "CDS" | "cds" => Ok(20),
-
-        "D0" | "d0" => Ok(31),
-        "D1" | "d1" => Ok(32),
-        "D2" | "d2" => Ok(33),
-        "D3" | "d3" => Ok(34),
-        "DR0" | "dr0" => Ok(35),
-        "DR1" | "dr1" => Ok(36),
-        "DR2" | "dr2" => Ok(37),
-        "DSS" | "dss" => Ok(38),
-        "DSP" | "dsp" => Ok(39),
-        "DDS" | "dds" => Ok(40),
-
-        _ => Err(String::from("Unknown register name."))
+// character offset from the start of the instruction and length of every operand
+#[cfg(test)]
+fn operand_spans(data: &str) -> Vec<(u64, u64)> {
+    match Syntax::parse_statement(data) {
+        Ok(Statement::Instruction { operands, .. }) => operands.iter().map(|o| (o.span.start, o.span.length)).collect(),
+        _ => vec![]
     }
 }
 
-fn para_immediate_num(immediate_number: &str) -> Result<u32, ParseIntError>{
-    if immediate_number.starts_with("0x") {
-        let immediate_number = immediate_number.trim_start_matches("0x");
-        return u32::from_str_radix(immediate_number, 16);
-    } else if immediate_number.starts_with("0o") {
-        let immediate_number = immediate_number.trim_start_matches("0o");
-        return u32::from_str_radix(immediate_number, 8);
-    } else if immediate_number.starts_with("0b") {
-        let immediate_number = immediate_number.trim_start_matches("0b");
-        return u32::from_str_radix(immediate_number, 2);
-    } else {
-        return u32::from_str_radix(immediate_number, 10);
+pub struct Image {
+    pub bytes: Vec<u8>,
+    // address ranges [start, end) that were written, sorted and merged when they touch
+    pub segments: Vec<(u64, u64)>,
+    // the part of segments that holds instructions and .INCBIN bytes, the rest is data
+    pub code: Vec<(u64, u64)>
+}
+
+impl Image {
+    pub fn new() -> Image {
+        Image { bytes: vec![], segments: vec![], code: vec![] }
+    }
+
+    // every byte can only be written once, a zero byte too
+    fn write(&mut self, address: u64, data: &[u8]) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = address + data.len() as u64;
+        if self.segments.iter().any(|&(start, stop)| address < stop && start < end) {
+            return Err(String::from("Instruction address conflict."));
+        }
+
+        if self.bytes.len() < end as usize {
+            self.bytes.resize(end as usize, 0);
+        }
+        self.bytes[address as usize..end as usize].copy_from_slice(data);
+
+        match self.segments.last_mut() {
+            Some(last) if last.1 == address => last.1 = end,
+            _ => self.segments.push((address, end))
+        }
+
+        Ok(())
+    }
+
+    fn merge_segments(&mut self) {
+        self.segments = merge(&self.segments);
+        self.code = merge(&self.code);
+    }
+}
+
+fn merge(ranges: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort();
+    let mut merged: Vec<(u64, u64)> = vec![];
+    for (start, end) in sorted {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end))
+        }
+    }
+    merged
+}
+
+const REGISTER_NAMES: [&str; 42] = [
+    "PC", "ZERO",
+    "A0", "A1", "A2", "A3", "AR0", "AR1", "AR2", "ASS", "ASP", "ADS",
+    "B0", "B1", "B2", "B3", "BR0", "BR1", "BR2", "BSS", "BSP", "BDS",
+    "C0", "C1", "C2", "C3", "CR0", "CR1", "CR2", "CSS", "CSP", "CDS",
+    "D0", "D1", "D2", "D3", "DR0", "DR1", "DR2", "DSS", "DSP", "DDS"
+];
+
+// register names are case insensitive
+pub fn get_register_label(register_name: &str) -> Result<u8, String> {
+    return match register_name.to_uppercase().as_str() {
+        "PC" => Ok(0b101001),
+        "ZERO" => Ok(0b000000),
+
+        "A0" => Ok(1),
+        "A1" => Ok(2),
+        "A2" => Ok(3),
+        "A3" => Ok(4),
+        "AR0" => Ok(5),
+        "AR1" => Ok(6),
+        "AR2" => Ok(7),
+        "ASS" => Ok(8),
+        "ASP" => Ok(9),
+        "ADS" => Ok(10),
+
+        "B0" => Ok(11),
+        "B1" => Ok(12),
+        "B2" => Ok(13),
+        "B3" => Ok(14),
+        "BR0" => Ok(15),
+        "BR1" => Ok(16),
+        "BR2" => Ok(17),
+        "BSS" => Ok(18),
+        "BSP" => Ok(19),
+        "BDS" => Ok(20),
+
+        "C0" => Ok(21),
+        "C1" => Ok(22),
+        "C2" => Ok(23),
+        "C3" => Ok(24),
+        "CR0" => Ok(25),
+        "CR1" => Ok(26),
+        "CR2" => Ok(27),
+        "CSS" => Ok(28),
+        "CSP" => Ok(29),
+        "CDS" => Ok(30),
+
+        "D0" => Ok(31),
+        "D1" => Ok(32),
+        "D2" => Ok(33),
+        "D3" => Ok(34),
+        "DR0" => Ok(35),
+        "DR1" => Ok(36),
+        "DR2" => Ok(37),
+        "DSS" => Ok(38),
+        "DSP" => Ok(39),
+        "DDS" => Ok(40),
+
+        _ => Err(String::from("Unknown register name"))
     }
 }
 
+// The inverse of get_register_label, every register has a label of its own
+pub fn get_register_name(label: u8) -> Option<&'static str> {
+    REGISTER_NAMES.iter().find(|n| get_register_label(n).ok() == Some(label)).copied()
+}
+
 #[derive(Debug, Clone)]
 struct Register {
     name: String,
@@ -232,42 +246,35 @@ struct Register {
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum Source {
     REG(Register),
     IMM(u32)
 }
 
-fn generate_register_ast(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<Vec<Source>, String> {
+fn generate_register_ast(operands: &[Operand], labels: &HashMap<String, u64>) -> Result<Vec<Source>, OperandError> {
     let mut result = vec![];
 
-    for item in register_info {
-        if item.starts_with('%') {
-            let register = item.trim_start_matches("%");
-            result.push(Source::REG( Register {
+    for (index, operand) in operands.iter().enumerate() {
+        match &operand.kind {
+            OperandKind::Register(register) => result.push(Source::REG( Register {
                 name: register.to_string(),
                 label: match get_register_label(register) {
                     Ok(v) => v,
-                    Err(e) => {
-                        println!("{}", e);
-                        panic!();
-                    }
-                }
-            }));
-        } else if item.starts_with('[') {
-            let imdn = item.trim_start_matches("[").trim_end_matches("]").trim();
-            result.push(Source::IMM(match para_immediate_num(imdn) {
-                Ok(v) => v,
-                Err(e) => {
-                    println!("{}", e);
-                    panic!();
-                }
-            }));
-        } else if labels.contains_key(item) {
-            result.push(Source::IMM(labels[item] as u32));
-        } else {
-            match calculate_expression(item, labels.clone()) {
-                Ok(val) => result.push(Source::IMM(val)),
-                Err(e) => return Err(format!("Invalid expression '{}': {}", item, e)),
+                    Err(e) => return Err(OperandError {
+                        operand: Some(index),
+                        message: format!("{} '{}'", e, operand.text),
+                        note: Some(format!("registers are {}", REGISTER_NAMES.join(", ")))
+                    })
+                }
+            })),
+            OperandKind::Address(expr) => match expr.evaluate(labels) {
+                Ok(v) => result.push(Source::IMM(v)),
+                Err(e) => return Err(OperandError::new(Some(index), format!("Invalid address '{}': {}", operand.text, e)))
+            },
+            OperandKind::Value(expr) => match expr.evaluate(labels) {
+                Ok(v) => result.push(Source::IMM(v)),
+                Err(e) => return Err(OperandError::new(Some(index), format!("Invalid expression '{}': {}", operand.text, e)))
             }
         }
     }
@@ -275,540 +282,144 @@ fn generate_register_ast(register_info: Vec<&str>, labels: HashMap<String, u64>)
     Ok(result)
 }
 
-fn calculate_expression(expression: &str, labels: HashMap<String, u64>) -> Result<u32, String> {
-    // 词法分析：将表达式分割成令牌
-    let mut tokens = vec![];
-    let mut token = String::new();
-    let mut curr_state = "";
-    for c in expression.chars() {
-        match c {
-            '+' | '-' | '*' | '/' | '%' | '(' | ')' | '&' | '|' | '^' => {
-                if !token.is_empty() {
-                    tokens.push(token.clone());
-                    token.clear();
-                }
-                tokens.push(c.to_string());
-                curr_state = "";
-            },
-            '<' => {
-                if curr_state == "slf" {
-                    if !token.is_empty() {
-                        tokens.push(token.clone());
-                        token.clear();
-                    }
-                    tokens.push("<<".to_string());
-                    curr_state = "";
-                } else if curr_state.is_empty() {
-                    curr_state = "slf";
-                } else {
-                    return Err(String::from("Invalid calculate symbol."));
-                }
-            },
-            '>' => {
-                if curr_state == "srf" {
-                    if !token.is_empty() {
-                        tokens.push(token.clone());
-                        token.clear();
-                    }
-                    tokens.push(">>".to_string());
-                    curr_state = "";
-                } else if curr_state.is_empty() {
-                    curr_state = "srf";
-                } else {
-                    return Err(String::from("Invalid calculate symbol."));
-                }
-            },
-            _ => {
-                // 如果当前处于状态，但遇到非状态字符，则先推送状态字符
-                if curr_state == "slf" {
-                    tokens.push("<".to_string());
-                    curr_state = "";
-                } else if curr_state == "srf" {
-                    tokens.push(">".to_string());
-                    curr_state = "";
-                }
-                token.push(c);
-            }
-        }
-    }
-    // 处理末尾可能的状态
-    if curr_state == "slf" {
-        tokens.push("<".to_string());
-    } else if curr_state == "srf" {
-        tokens.push(">".to_string());
-    }
-    // 处理最后一个令牌
-    if !token.is_empty() {
-        tokens.push(token);
-    }
-
-    // 定义令牌类型
-    #[derive(Debug, Clone)]
-    enum Token {
-        Op(String),
-        Num(i64),
+pub fn calculate_expression(expression: &str, labels: HashMap<String, u64>) -> Result<u32, String> {
+    match Syntax::parse_expression(expression) {
+        Ok(expr) => expr.evaluate(&labels),
+        Err(e) => Err(e.message)
     }
+}
 
-    // 将字符串令牌转换为Token枚举
-    let mut parsed_tokens = vec![];
-    for token in tokens {
-        let token = token.trim();
-        if token.is_empty() {
-            continue;
-        }
+#[derive(Debug, Clone, PartialEq)]
+struct OperandError {
+    // index of the operand the error is about, None when it is about the whole instruction
+    operand: Option<usize>,
+    message: String,
+    note: Option<String>
+}
 
-        // 检查是否为数字字面量
-        if token.starts_with("0x") {
-            match i64::from_str_radix(&token[2..], 16) {
-                Ok(v) => parsed_tokens.push(Token::Num(v)),
-                Err(e) => return Err(format!("Invalid hex number '{}': {}", token, e)),
-            }
-        } else if token.starts_with("0o") {
-            match i64::from_str_radix(&token[2..], 8) {
-                Ok(v) => parsed_tokens.push(Token::Num(v)),
-                Err(e) => return Err(format!("Invalid octal number '{}': {}", token, e)),
-            }
-        } else if token.starts_with("0b") {
-            match i64::from_str_radix(&token[2..], 2) {
-                Ok(v) => parsed_tokens.push(Token::Num(v)),
-                Err(e) => return Err(format!("Invalid binary number '{}': {}", token, e)),
-            }
-        } else if token == "+" || token == "-" || token == "*" || token == "/" || token == "%" ||
-                  token == "<<" || token == ">>" || token == "(" || token == ")" || token == "&" || token == "|" || token == "^" {
-            parsed_tokens.push(Token::Op(token.to_string()));
-        } else {
-            // 尝试解析为十进制数字
-            match token.parse::<i64>() {
-                Ok(v) => parsed_tokens.push(Token::Num(v)),
-                Err(_) => {
-                    // 检查是否为标签
-                    match labels.get(token) {
-                        Some(&l) => parsed_tokens.push(Token::Num(l as i64)),
-                        None => return Err(format!("Unknown symbol: {}", token)),
-                    }
-                }
-            }
-        }
+impl OperandError {
+    fn new(operand: Option<usize>, message: String) -> OperandError {
+        OperandError { operand, message, note: None }
     }
+}
 
-    // 普拉特解析器实现
-    #[derive(Debug)]
-    enum Expr {
-        Number(i64),
-        BinaryOp(String, Box<Expr>, Box<Expr>),
-    }
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Target,
+    Source0,
+    Source1
+}
 
-    // 获取运算符优先级
-    fn precedence(op: &str) -> i32 {
-        match op {
-            "<<" | ">>" | "&" | "|" | "^" => 1,
-            "+" | "-" => 2,
-            "*" | "/" | "%" => 3,
-            ")" => -1,
-            "(" => -1,
-            _ => 0,
+impl Slot {
+    fn invalid_reg(self, constraint: &Constraint) -> &Vec<String> {
+        return match self {
+            Slot::Target => &constraint.target_invalid_reg,
+            Slot::Source0 => &constraint.source_0_invalid_reg,
+            Slot::Source1 => &constraint.source_1_invalid_reg
         }
     }
+}
 
-    // 解析表达式
-    struct Parser {
-        tokens: Vec<Token>,
-        pos: usize,
-    }
-
-    impl Parser {
-        fn new(tokens: Vec<Token>) -> Self {
-            Parser { tokens, pos: 0 }
-        }
-
-        fn current(&self) -> Option<&Token> {
-            self.tokens.get(self.pos)
-        }
-
-        fn consume(&mut self) -> Option<Token> {
-            if self.pos < self.tokens.len() {
-                let token = self.tokens[self.pos].clone();
-                self.pos += 1;
-                Some(token)
-            } else {
-                None
-            }
-        }
-
-        fn parse_expression(&mut self, min_precedence: i32) -> Result<Expr, String> {
-            // 解析左侧表达式
-            let mut left = self.parse_primary()?;
-
-            while let Some(Token::Op(op)) = self.current() {
-                let prec = precedence(op);
-                if prec < min_precedence {
-                    break;
-                }
-
-                // 左结合性，所以当前优先级+1
-                let next_min_precedence = prec + 1;
-                let op = op.clone();
-                self.consume(); // 消费运算符
-
-                let right = self.parse_expression(next_min_precedence)?;
-                left = Expr::BinaryOp(op, Box::new(left), Box::new(right));
-            }
+fn get_register<'a>(rast: &'a [Source], index: usize, slot: Slot, slot_name: &str, constraint: &Constraint, op_name: &str) -> Result<&'a Register, OperandError> {
+    let register = match rast.get(index) {
+        Some(Source::REG(r)) => r,
+        Some(Source::IMM(_)) => return Err(OperandError::new(Some(index), format!("{}: Invalid {} register.", op_name, slot_name))),
+        None => return Err(OperandError::new(None, format!("{}: missing parameters.", op_name)))
+    };
 
-            Ok(left)
-        }
+    let invalid_reg = slot.invalid_reg(constraint);
+    if invalid_reg.iter().any(|r| r.eq_ignore_ascii_case(&register.name)) {
+        let valid_reg = REGISTER_NAMES.iter().filter(|n| !invalid_reg.iter().any(|r| r.eq_ignore_ascii_case(n))).copied().collect::<Vec<&str>>();
+        return Err(OperandError {
+            operand: Some(index),
+            message: format!("{}: {} register can't be {}", op_name, slot_name, register.name),
+            note: Some(format!("valid registers for this slot are {}", valid_reg.join(", ")))
+        });
+    }
 
-        fn parse_primary(&mut self) -> Result<Expr, String> {
-            match self.consume() {
-                Some(Token::Num(n)) => Ok(Expr::Number(n)),
-                Some(Token::Op(op)) if op == "(" => {
-                    let expr = self.parse_expression(0)?;
-                    match self.current() {
-                        Some(Token::Op(closing)) if closing == ")" => {
-                            self.consume();
-                            Ok(expr)
-                        }
-                        _ => Err("Expected ')'".to_string()),
-                    }
-                }
-                Some(Token::Op(op)) if op == "-" => {
-                    // 一元负号（可选实现）
-                    let expr = self.parse_primary()?;
-                    Ok(Expr::BinaryOp("*".to_string(), Box::new(Expr::Number(-1)), Box::new(expr)))
-                }
-                _ => Err("Expected number or '('".to_string()),
-            }
-        }
+    Ok(register)
+}
 
-        fn parse(&mut self) -> Result<Expr, String> {
-            let expr = self.parse_expression(0)?;
-            if self.pos < self.tokens.len() {
-                Err("Unexpected tokens at end of expression".to_string())
-            } else {
-                Ok(expr)
-            }
-        }
-    }
+fn get_immediate(rast: &[Source], index: usize, max: u32, immediate_name: &str, op_name: &str) -> Result<u32, OperandError> {
+    let &immediate_number = match rast.get(index) {
+        Some(Source::IMM(i)) => i,
+        Some(Source::REG(_)) => return Err(OperandError::new(Some(index), format!("{}: Invalid {}.", op_name, immediate_name))),
+        None => return Err(OperandError::new(None, format!("{}: missing parameters.", op_name)))
+    };
 
-    let mut parser = Parser::new(parsed_tokens);
-    let expr = parser.parse()?;
-
-    // 计算表达式值
-    fn eval(expr: Expr) -> Result<i64, String> {
-        match expr {
-            Expr::Number(n) => Ok(n),
-            Expr::BinaryOp(op, left, right) => {
-                let left_val = eval(*left)?;
-                let right_val = eval(*right)?;
-                match op.as_str() {
-                    "+" => Ok(left_val + right_val),
-                    "-" => Ok(left_val - right_val),
-                    "*" => Ok(left_val * right_val),
-                    "/" => {
-                        if right_val == 0 {
-                            return Err("Division by zero".to_string());
-                        }
-                        Ok(left_val / right_val)
-                    }
-                    "%" => {
-                        if right_val == 0 {
-                            return Err("Modulo by zero".to_string());
-                        }
-                        Ok(left_val % right_val)
-                    }
-                    "<<" => Ok(left_val << right_val),
-                    ">>" => Ok(left_val >> right_val),
-                    "&" => Ok(left_val & right_val),
-                    "|" => Ok(left_val | right_val),
-                    "^" => Ok(left_val ^ right_val),
-                    _ => Err(format!("Unknown operator: {}", op)),
-                }
-            }
-        }
+    if immediate_number > max {
+        return Err(OperandError {
+            operand: Some(index),
+            message: format!("{}: {} is grater then {}", op_name, immediate_name, max),
+            note: Some(format!("{} is {:#X}, the largest value for this slot is {:#X}", immediate_name, immediate_number, max))
+        });
     }
 
-    let result = eval(expr)?;
-    // 转换为u32，检查溢出
-    if result < 0 || result > u32::MAX as i64 {
-        return Err("Expression result out of u32 range".to_string());
-    }
-    Ok(result as u32)
+    Ok(immediate_number)
 }
 
 struct InstDiffTypePars {}
 
 impl InstDiffTypePars {
-    fn pars_ti(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let &immediate_number = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(_) => return Err(format!("{}: Invalid immediate number.", op_name)),
-                    Source::IMM(i) => i
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("{}: target register can't be {}", op_name, target_register.name));
-        }
-
-        if immediate_number > constraint.immediate_0_number_max {
-            return Err(format!("{}: immediat number is grater then {}", op_name, constraint.immediate_0_number_max));
-        }
+    fn pars_ti(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
+        let immediate_number = get_immediate(&rast, 1, constraint.immediate_0_number_max, "immediate number", op_name)?;
 
         let bin_code = ((target_register.label as u32) << 16) | immediate_number;
         return Ok(bin_code);
     }
 
-    fn pars_s(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
+    fn pars_s(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
 
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("{}: target register can't be {}", op_name, target_register.name));
-        }
-
-        let bin_code = ((target_register.label as u32) << 16);
+        let bin_code = (target_register.label as u32) << 16;
         return Ok(bin_code);
     }
 
-    fn pars_i(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let &immediate_number = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(_) => return Err(format!("{}: Invalid immediate number.", op_name)),
-                    Source::IMM(i) => i
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if immediate_number > constraint.immediate_0_number_max {
-            return Err(format!("immediat number is grater then {}", constraint.immediate_0_number_max));
-        }
+    fn pars_i(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let immediate_number = get_immediate(&rast, 0, constraint.immediate_0_number_max, "immediate number", op_name)?;
 
         return Ok(immediate_number);
     }
 
-    fn pars_ss(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let source_0_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source 0 register.", op_name))
-                }
-            },
-            None => return Err(String::from("LOAD8: missing parameters."))
-        };
-
-        let source_1_register = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source 1 register.", op_name))
-                }
-            },
-            None => return Err(String::from("LOAD8: missing parameters."))
-        };
-
-        if constraint.source_0_invalid_reg.contains(&source_0_register.name) {
-            return Err(format!("{}: source 0 register can't be {}", op_name, source_0_register.name));
-        }
-
-        if constraint.source_1_invalid_reg.contains(&source_1_register.name) {
-            return Err(format!("{}: source 1 register can't be {}", op_name, source_1_register.name));
-        }
+    fn pars_ss(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let source_0_register = get_register(&rast, 0, Slot::Source0, "source 0", &constraint, op_name)?;
+        let source_1_register = get_register(&rast, 1, Slot::Source1, "source 1", &constraint, op_name)?;
 
         let bin_code = ((source_0_register.label as u32) << 10) | ((source_1_register.label as u32) << 4);
         return Ok(bin_code);
     }
 
-    fn pars_ts(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let source_register = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("target register can't be {}", target_register.name));
-        }
-
-        if constraint.source_0_invalid_reg.contains(&source_register.name) {
-            return Err(format!("source register can't be {}", source_register.name));
-        }
+    fn pars_ts(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
+        let source_register = get_register(&rast, 1, Slot::Source0, "source", &constraint, op_name)?;
 
         let bin_code = ((target_register.label as u32) << 16) | ((source_register.label as u32) << 10);
         return Ok(bin_code);
     }
 
-    fn pars_tsi(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let source_register = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let &immediate_number = match rast.get(2) {
-            Some(s) => {
-                match s {
-                    Source::REG(_) => return Err(format!("{}: Invalid immediate number.", op_name)),
-                    Source::IMM(i) => i
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("target register can't be {}", target_register.name));
-        }
-
-        if constraint.source_0_invalid_reg.contains(&source_register.name) {
-            return Err(format!("source register can't be {}", source_register.name));
-        }
-
-        if immediate_number > constraint.immediate_0_number_max {
-            return Err(format!("immediat number is grater then {}", constraint.immediate_0_number_max));
-        }
+    fn pars_tsi(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
+        let source_register = get_register(&rast, 1, Slot::Source0, "source", &constraint, op_name)?;
+        let immediate_number = get_immediate(&rast, 2, constraint.immediate_0_number_max, "immediate number", op_name)?;
 
         let bin_code = ((target_register.label as u32) << 16) | ((source_register.label as u32) << 10) | immediate_number;
         return Ok(bin_code);
     }
 
-    fn pars_tss(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let source_0_register = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let source_1_register = match rast.get(2) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid source register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("target register can't be {}", target_register.name));
-        }
-
-        if constraint.source_0_invalid_reg.contains(&source_0_register.name) {
-            return Err(format!("source 0 register can't be {}", source_0_register.name));
-        }
-
-        if constraint.source_1_invalid_reg.contains(&source_1_register.name) {
-            return Err(format!("source 1 register can't be {}", source_1_register.name));
-        }
+    fn pars_tss(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
+        let source_0_register = get_register(&rast, 1, Slot::Source0, "source 0", &constraint, op_name)?;
+        let source_1_register = get_register(&rast, 2, Slot::Source1, "source 1", &constraint, op_name)?;
 
         let bin_code = ((target_register.label as u32) << 16) | ((source_0_register.label as u32) << 10) | ((source_1_register.label as u32) << 4);
         return Ok(bin_code);
     }
 
-    fn pars_tii(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, String> {
-        let target_register = match rast.get(0) {
-            Some(s) => {
-                match s {
-                    Source::REG(r) => r,
-                    Source::IMM(i) => return Err(format!("{}: Invalid target register.", op_name))
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let &immediate_0_number = match rast.get(1) {
-            Some(s) => {
-                match s {
-                    Source::REG(_) => return Err(format!("{}: Invalid immediate number.", op_name)),
-                    Source::IMM(i) => i
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        let &immediate_1_number = match rast.get(2) {
-            Some(s) => {
-                match s {
-                    Source::REG(_) => return Err(format!("{}: Invalid immediate number.", op_name)),
-                    Source::IMM(i) => i
-                }
-            },
-            None => return Err(format!("{}: missing parameters.", op_name))
-        };
-
-        if constraint.target_invalid_reg.contains(&target_register.name) {
-            return Err(format!("target register can't be {}", target_register.name));
-        }
-
-        if immediate_0_number > constraint.immediate_0_number_max {
-            return Err(format!("immediat number 0 is grater then {}", constraint.immediate_0_number_max));
-        }
-
-        if immediate_1_number > constraint.immediate_1_number_max {
-            return Err(format!("immediat number 1 is grater then {}", constraint.immediate_1_number_max));
-        }
+    fn pars_tii(rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        let target_register = get_register(&rast, 0, Slot::Target, "target", &constraint, op_name)?;
+        let immediate_0_number = get_immediate(&rast, 1, constraint.immediate_0_number_max, "immediate number 0", op_name)?;
+        let immediate_1_number = get_immediate(&rast, 2, constraint.immediate_1_number_max, "immediate number 1", op_name)?;
 
         let bin_code = ((target_register.label as u32) << 16) | (immediate_0_number << 10) | (immediate_1_number << 4);
         return Ok(bin_code);
@@ -818,459 +429,146 @@ impl InstDiffTypePars {
 struct InstPars {}
 
 impl InstPars {
-    fn pars_load8(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "LOAD8";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ti(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b0000_0000_01 << 22) | b),
-                    Err(e) => {
-                        match InstDiffTypePars::pars_s(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0000_10 << 22) | b),
-                            Err(e) => return Err(e)
-                        }
-                    }
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_ti(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0000_10 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
-    }
-
-    fn pars_load16(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "LOAD16";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ti(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b0000_0000_11 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_s(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0001_00 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_ti(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_00 << 22) | b),
-                    Err(e) => Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
-    }
-
-    fn pars_load32(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "LOAD32";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ti(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b0000_0001_10 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_s(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0001_01 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_tss(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_01 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+    fn pars_form(kind: FormKind, rast: Vec<Source>, constraint: Constraint, op_name: &str) -> Result<u32, OperandError> {
+        return match kind {
+            FormKind::I => InstDiffTypePars::pars_i(rast, constraint, op_name),
+            FormKind::S => InstDiffTypePars::pars_s(rast, constraint, op_name),
+            FormKind::Ss => InstDiffTypePars::pars_ss(rast, constraint, op_name),
+            FormKind::Ti => InstDiffTypePars::pars_ti(rast, constraint, op_name),
+            FormKind::Ts => InstDiffTypePars::pars_ts(rast, constraint, op_name),
+            FormKind::Tsi => InstDiffTypePars::pars_tsi(rast, constraint, op_name),
+            FormKind::Tss => InstDiffTypePars::pars_tss(rast, constraint, op_name),
+            FormKind::Tii => InstDiffTypePars::pars_tii(rast, constraint, op_name)
+        }
     }
+}
 
-    fn pars_store8(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "STORE8";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
+// Encodes a single instruction without reporting why it failed, used to check the disassembler output
+pub fn encode_instruction(inst: &Instruction, operands: Vec<&str>, labels: HashMap<String, u64>) -> Option<u32> {
+    pars_instruction(inst, operands, labels).ok()
+}
 
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_10 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_tss(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_10 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+// the operands as separate strings, each one is parsed on its own
+fn pars_instruction(inst: &Instruction, register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, Vec<(Option<FormKind>, OperandError)>> {
+    let mut operands = vec![];
+    for (index, text) in register_info.into_iter().enumerate() {
+        match Syntax::parse_operand(text) {
+            Ok(o) => operands.push(o),
+            Err(e) => return Err(vec![(None, OperandError::new(Some(index), e.message))])
+        }
     }
 
-    fn pars_store16(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "STORE16";
+    pars_operands(inst, &operands, &labels)
+}
 
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
+// Forms with a matching argument count are tried in the order of the ISA description,
+// the first one that accepts the operands decides the encoding. On failure the error of
+// every form that was tried is returned together with the form.
+fn pars_operands(inst: &Instruction, operands: &[Operand], labels: &HashMap<String, u64>) -> Result<u32, Vec<(Option<FormKind>, OperandError)>> {
+    let op_name = inst.name.as_str();
 
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
+    let rast = match generate_register_ast(operands, labels) {
+        Ok(r) => r,
+        Err(e) => return Err(vec![(None, e)])
+    };
 
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_11 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_tss(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0001_11 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+    let mut errors = vec![];
+    for form in inst.forms.iter().filter(|f| f.args == rast.len()) {
+        match InstPars::pars_form(form.kind, rast.clone(), inst.constraint.clone(), op_name) {
+            Ok(b) => return Ok((form.opcode << OPCODE_SHIFT) | b),
+            Err(e) => errors.push((Some(form.kind), e))
+        }
     }
 
-    fn pars_store32(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "STORE32";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0010_00 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            3 => {
-                match InstDiffTypePars::pars_tss(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0010_00 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+    if !errors.is_empty() {
+        return Err(errors);
     }
 
-    fn pars_move(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "MOVE";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b0000_0010_01 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+    let mut args = inst.forms.iter().map(|f| f.args).collect::<Vec<usize>>();
+    args.sort();
+    args.dedup();
+    let args = args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+    let note = Some(format!("{} takes {} arguments", op_name, args.join(" or ")));
+    if rast.len() < inst.min_args() {
+        Err(vec![(None, OperandError { operand: None, message: format!("{}: Too few arguments!", op_name), note })])
+    } else {
+        Err(vec![(None, OperandError { operand: Some(inst.max_args()), message: format!("{}: Too much arguments!", op_name), note })])
     }
+}
 
-    fn pars_add(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "ADD";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
 
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
+    #[test]
+    fn test_pars_instruction_forms() {
+        let isa = InstructionSet::load(None).unwrap();
+        let labels = HashMap::new();
+        let pars = |name: &str, args: Vec<&str>| pars_instruction(isa.get(name).unwrap(), args, labels.clone());
 
-        match rast.len() {
-            1 | 2 => return Err(format!("{}: Too few arguments!", op_name)),
-            3 => {
-                match InstDiffTypePars::pars_tss(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b1000_0000_01 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_tsi(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1000_0000_00 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+        assert_eq!(pars("LOAD8", vec!["%a2", "[0xFF]"]), Ok(0x004300FF));
+        assert_eq!(pars("EQ", vec!["%ar0", "%a3", "[0]"]), Ok(0x90851000));
+        assert_eq!(pars("EQ", vec!["%ar0", "%a3", "%a1"]), Ok(0x90C51020));
+        assert_eq!(pars("JMP", vec!["%b1"]), Ok(0xC04C0000));
+        assert_eq!(pars("JMP", vec!["[0x3F0000]"]), Ok(0xC03F0000));
+        assert!(pars("ADD", vec!["%a0", "%a1"]).is_err());
+        assert!(pars("MOVE", vec!["%a0", "%a1", "%a2"]).is_err());
+        assert!(pars("LOAD8", vec!["%PC", "[1]"]).is_err());
     }
 
-    fn pars_sub(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "SUB";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0xFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 | 2 => return Err(format!("{}: Too few arguments!", op_name)),
-            3 => {
-                match InstDiffTypePars::pars_tss(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b1000_0000_01 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_tsi(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1000_0000_00 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
+    #[test]
+    fn test_register_labels() {
+        // CDS had the label of BDS, so an instruction written with CDS used BDS
+        assert_eq!(get_register_label("CDS"), Ok(30));
+        assert_eq!(get_register_label("bds"), Ok(20));
+        for name in REGISTER_NAMES {
+            assert_eq!(get_register_label(name).ok().and_then(get_register_name), Some(name));
         }
     }
 
-    fn pars_eq(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "EQ";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0x3FF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 | 2 => return Err(format!("{}: Too few arguments!", op_name)),
-            3 => match InstDiffTypePars::pars_tss(rast.clone(), constraint.clone(), op_name) {
-                Ok(b) => return Ok((0b1001_0000_11 << 22) | b),
-                Err(e1) => {
-                    match InstDiffTypePars::pars_tsi(rast, constraint, op_name) {
-                        Ok(b) => return Ok((0b1001_0000_10 << 22) | b),
-                        Err(e) => return Err(e1 + "\n" + &e)
-                    }
-                }
-            }
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
-    }
-
-    fn pars_jmp(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "JMP";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0x3FFFFF,
-            immediate_1_number_max: 0
-        };
+    #[test]
+    fn test_pars_instructions_collects_errors() {
+        let isa = InstructionSet::load(None).unwrap();
+        let instr = |data: &str, address: u64, line: u64| Instr { data: data.to_string(), statement: Syntax::parse_statement(data).unwrap(), address, file: String::from("test.maasm"), line, column: 5, scope: Default::default(), included_from: vec![], replacements: vec![] };
+        let instructions = vec![
+            instr("LOAD8 %q1, [0]", 0, 1),
+            instr("JMP [0]", 4, 2),
+            instr("FOO %a0", 8, 3),
+            instr("JMP [4]", 4, 4)
+        ];
 
-        match rast.len() {
-            1 => {
-                match InstDiffTypePars::pars_i(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b1100_0000_00 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_s(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0000_01 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            2 => {
-                match InstDiffTypePars::pars_ss(rast, constraint, op_name) {
-                    Ok(b) => return Ok((0b1100_0000_01 << 22) | b),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+        let errors = pars_instructions(instructions, vec![], HashMap::new(), &isa).err().unwrap();
+        let lines = errors.iter().map(|e| (e.line, e.column)).collect::<Vec<(u64, u64)>>();
+        assert_eq!(lines, vec![(1, 11), (3, 5), (4, 5)]);
     }
 
-    fn pars_ojmp(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "OJMP";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0x3FFFFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ti(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b1100_0000_10 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0000_11 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        } 
+    #[test]
+    fn test_image_segments() {
+        let mut image = Image::new();
+        image.write(0x10, &[1, 2, 3, 4]).unwrap();
+        image.write(0x14, &[5, 6, 7, 8]).unwrap();
+        image.write(0x4, &[9, 0, 0, 0]).unwrap();
+        image.write(0x8, &[10]).unwrap();
+        image.write(0x18, &[11]).unwrap();
+        image.write(0x1, &[12, 13, 0]).unwrap();
+        assert!(image.write(0x12, &[1]).is_err());
+        // zero bytes are taken too
+        assert!(image.write(0x6, &[1]).is_err());
+        assert!(image.write(0x3, &[0, 0]).is_err());
+        image.merge_segments();
+
+        assert_eq!(image.segments, vec![(0x1, 0x9), (0x10, 0x19)]);
+        assert_eq!(image.bytes.len(), 0x19);
+        assert_eq!(image.bytes[0x1..0x9], [12, 13, 0, 9, 0, 0, 0, 10]);
     }
 
-    fn pars_zjmp(register_info: Vec<&str>, labels: HashMap<String, u64>) -> Result<u32, String> {
-        let op_name = "ZJMP";
-
-        let rast = match generate_register_ast(register_info, labels) {
-            Ok(v) => v,
-            Err(e) => return Err(e)
-        };
-
-        let constraint = Constraint {
-            target_invalid_reg: vec![String::from("PC"), String::from("ZERO")],
-            source_0_invalid_reg: vec![],
-            source_1_invalid_reg: vec![],
-            immediate_0_number_max: 0x3FFFFF,
-            immediate_1_number_max: 0
-        };
-
-        match rast.len() {
-            1 => return Err(format!("{}: Too few arguments!", op_name)),
-            2 => {
-                match InstDiffTypePars::pars_ti(rast.clone(), constraint.clone(), op_name) {
-                    Ok(b) => return Ok((0b1100_0001_00 << 22) | b),
-                    Err(e1) => {
-                        match InstDiffTypePars::pars_ts(rast, constraint, op_name) {
-                            Ok(b) => return Ok((0b1100_0001_01 << 22) | b),
-                            Err(e) => return Err(e1 + "\n" + &e)
-                        }
-                    }
-                }
-            },
-            _ => return Err(format!("{}: Too much arguments!", op_name))
-        }
+    #[test]
+    fn test_operand_spans() {
+        assert_eq!(operand_spans("ADD %a0,  %a1 , [1]"), vec![(4, 3), (10, 3), (16, 3)]);
+        assert_eq!(operand_spans("LOAD32 %b1, check_ram_loop & 0x0000FFFF"), vec![(7, 3), (12, 27)]);
+        assert_eq!(operand_spans("NOP"), vec![]);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
 
     #[test]
     fn test_calculate_expression_basic() {
@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io::read_to_string;
+use taplo::dom::Node;
+
+const DEFAULT_ISA: &str = include_str!("../docs/instructions.toml");
+
+#[derive(Debug, Clone, Default)]
+pub struct Constraint {
+    pub target_invalid_reg: Vec<String>,
+    pub source_0_invalid_reg: Vec<String>,
+    pub source_1_invalid_reg: Vec<String>,
+    pub immediate_0_number_max: u32,
+    pub immediate_1_number_max: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormKind {
+    I,
+    S,
+    Ss,
+    Ti,
+    Ts,
+    Tsi,
+    Tss,
+    Tii
+}
+
+impl FormKind {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            FormKind::I => "i",
+            FormKind::S => "s",
+            FormKind::Ss => "ss",
+            FormKind::Ti => "ti",
+            FormKind::Ts => "ts",
+            FormKind::Tsi => "tsi",
+            FormKind::Tss => "tss",
+            FormKind::Tii => "tii"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<FormKind> {
+        return match name {
+            "i" => Some(FormKind::I),
+            "s" => Some(FormKind::S),
+            "ss" => Some(FormKind::Ss),
+            "ti" => Some(FormKind::Ti),
+            "ts" => Some(FormKind::Ts),
+            "tsi" => Some(FormKind::Tsi),
+            "tss" => Some(FormKind::Tss),
+            "tii" => Some(FormKind::Tii),
+            _ => None
+        }
+    }
+}
+
+// What an instruction does, the simulator runs it by this and the operand form
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Load,
+    Store,
+    Move,
+    Add,
+    Sub,
+    Eq,
+    Jump,
+    JumpNotZero,
+    JumpZero
+}
+
+impl Operation {
+    fn from_name(name: &str) -> Option<Operation> {
+        return match name {
+            "load" => Some(Operation::Load),
+            "store" => Some(Operation::Store),
+            "move" => Some(Operation::Move),
+            "add" => Some(Operation::Add),
+            "sub" => Some(Operation::Sub),
+            "eq" => Some(Operation::Eq),
+            "jump" => Some(Operation::Jump),
+            "jump_not_zero" => Some(Operation::JumpNotZero),
+            "jump_zero" => Some(Operation::JumpZero),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OperandForm {
+    pub args: usize,
+    pub kind: FormKind,
+    pub opcode: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub name: String,
+    pub constraint: Constraint,
+    pub forms: Vec<OperandForm>,
+    // None when the description doesn't say, the assembler doesn't need it
+    pub operation: Option<Operation>,
+    // bytes moved by a load or store
+    pub size: u32
+}
+
+impl Instruction {
+    pub fn min_args(&self) -> usize {
+        self.forms.iter().map(|f| f.args).min().unwrap_or(0)
+    }
+
+    pub fn max_args(&self) -> usize {
+        self.forms.iter().map(|f| f.args).max().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InstructionSet {
+    instructions: Vec<Instruction>
+}
+
+impl InstructionSet {
+    // Without a path the description in docs/instructions.toml, built into the binary, is used.
+    pub fn load(file_path: Option<String>) -> Result<InstructionSet, String> {
+        let file_path = match file_path {
+            Some(p) => p,
+            None => return InstructionSet::from_toml(DEFAULT_ISA)
+        };
+
+        let isa_file = match File::open(&file_path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("{}: {}", file_path, e))
+        };
+
+        let isa_data = match read_to_string(isa_file) {
+            Ok(d) => d,
+            Err(e) => return Err(format!("{}: {}", file_path, e))
+        };
+
+        match InstructionSet::from_toml(&isa_data) {
+            Ok(isa) => Ok(isa),
+            Err(e) => Err(format!("{}: {}", file_path, e))
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<InstructionSet, String> {
+        let parse = taplo::parser::parse(source);
+        if let Some(e) = parse.errors.first() {
+            return Err(format!("Invalid instruction set description: {}", e));
+        }
+
+        let root = parse.into_dom();
+        let root = match root.as_table() {
+            Some(t) => t.clone(),
+            None => return Err(String::from("Invalid instruction set description."))
+        };
+
+        let mut instructions = vec![];
+        for (key, node) in root.entries().read().iter() {
+            let name = key.value().to_string();
+            let table = match node.as_table() {
+                Some(t) => t,
+                None => return Err(format!("{}: instruction description must be a table.", name))
+            };
+
+            let constraint = Constraint {
+                target_invalid_reg: get_reg_list(table.get("target_invalid_reg"), &name)?,
+                source_0_invalid_reg: get_reg_list(table.get("source_0_invalid_reg"), &name)?,
+                source_1_invalid_reg: get_reg_list(table.get("source_1_invalid_reg"), &name)?,
+                immediate_0_number_max: get_u32(table.get("immediate_0_number_max"), &name)?.unwrap_or(0),
+                immediate_1_number_max: get_u32(table.get("immediate_1_number_max"), &name)?.unwrap_or(0)
+            };
+
+            let forms = match table.get("forms") {
+                Some(f) => get_forms(f, &name)?,
+                None => return Err(format!("{}: missing operand forms.", name))
+            };
+
+            let operation = match table.get("operation").as_ref().map(|o| o.as_str()) {
+                Some(Some(o)) => match Operation::from_name(o.value()) {
+                    Some(o) => Some(o),
+                    None => return Err(format!("{}: unknown operation '{}'.", name, o.value()))
+                },
+                Some(None) => return Err(format!("{}: operation must be a string.", name)),
+                None => None
+            };
+
+            let size = match get_u32(table.get("size"), &name)? {
+                Some(s @ (1 | 2 | 4)) => s,
+                Some(s) => return Err(format!("{}: size {} isn't 1, 2 or 4 bytes.", name, s)),
+                None => 4
+            };
+
+            if instructions.iter().any(|i: &Instruction| i.name == name) {
+                return Err(format!("{}: instruction defined twice.", name));
+            }
+
+            instructions.push(Instruction { name, constraint, forms, operation, size });
+        }
+
+        Ok(InstructionSet { instructions })
+    }
+
+    // Mnemonics are accepted in upper or lower case, as the hand written parser did.
+    pub fn get(&self, mnemonic: &str) -> Option<&Instruction> {
+        self.instructions.iter().find(|i| i.name.eq_ignore_ascii_case(mnemonic))
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn mnemonics(&self) -> Vec<&str> {
+        self.instructions.iter().map(|i| i.name.as_str()).collect()
+    }
+}
+
+fn get_u32(node: Option<Node>, name: &str) -> Result<Option<u32>, String> {
+    let node = match node {
+        Some(n) => n,
+        None => return Ok(None)
+    };
+
+    let value = match node.as_integer().and_then(|i| i.value().as_positive()) {
+        Some(v) => v,
+        None => return Err(format!("{}: expected a positive integer.", name))
+    };
+
+    match u32::try_from(value) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => Err(format!("{}: {} doesn't fit in 32 bits.", name, value))
+    }
+}
+
+fn get_reg_list(node: Option<Node>, name: &str) -> Result<Vec<String>, String> {
+    let node = match node {
+        Some(n) => n,
+        None => return Ok(vec![])
+    };
+
+    let array = match node.as_array() {
+        Some(a) => a,
+        None => return Err(format!("{}: register list must be an array.", name))
+    };
+
+    let mut result = vec![];
+    for item in array.items().read().iter() {
+        match item.as_str() {
+            Some(s) => result.push(s.value().to_string()),
+            None => return Err(format!("{}: register names must be strings.", name))
+        }
+    }
+
+    Ok(result)
+}
+
+fn get_forms(node: Node, name: &str) -> Result<Vec<OperandForm>, String> {
+    let array = match node.as_array() {
+        Some(a) => a,
+        None => return Err(format!("{}: forms must be an array.", name))
+    };
+
+    let mut result = vec![];
+    for item in array.items().read().iter() {
+        let form = match item.as_table() {
+            Some(t) => t,
+            None => return Err(format!("{}: every form must be a table.", name))
+        };
+
+        let args = match get_u32(form.get("args"), name)? {
+            Some(a) => a as usize,
+            None => return Err(format!("{}: form is missing 'args'.", name))
+        };
+
+        let kind = match form.get("kind").as_ref().and_then(|k| k.as_str()) {
+            Some(k) => match FormKind::from_name(k.value()) {
+                Some(k) => k,
+                None => return Err(format!("{}: unknown operand form '{}'.", name, k.value()))
+            },
+            None => return Err(format!("{}: form is missing 'kind'.", name))
+        };
+
+        let opcode = match get_u32(form.get("opcode"), name)? {
+            Some(o) => o,
+            None => return Err(format!("{}: form is missing 'opcode'.", name))
+        };
+
+        if opcode > 0x3FF {
+            return Err(format!("{}: opcode {:#b} is wider than 10 bits.", name, opcode));
+        }
+
+        result.push(OperandForm { args, kind, opcode });
+    }
+
+    if result.is_empty() {
+        return Err(format!("{}: missing operand forms.", name));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_isa_loads() {
+        let isa = InstructionSet::load(None).unwrap();
+        let load8 = isa.get("LOAD8").unwrap();
+        assert_eq!(load8.forms.len(), 3);
+        assert_eq!(load8.forms[0].kind, FormKind::Ti);
+        assert_eq!(load8.forms[0].opcode, 1);
+        assert_eq!(load8.constraint.immediate_0_number_max, 0xFF);
+        assert_eq!(load8.constraint.target_invalid_reg, vec!["PC", "ZERO"]);
+        assert_eq!(load8.operation, Some(Operation::Load));
+        assert_eq!(load8.size, 1);
+        assert_eq!(isa.get("ZJMP").unwrap().operation, Some(Operation::JumpZero));
+        assert!(isa.get("load8").is_some());
+        assert!(isa.get("Load8").is_some());
+        assert!(isa.get("LOAD").is_none());
+    }
+
+    #[test]
+    fn test_isa_errors() {
+        assert!(InstructionSet::from_toml("[NOP]\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP]\nforms = [{ args = 0, kind = \"x\", opcode = 0 }]\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP]\nforms = [{ args = 0, kind = \"i\", opcode = 0x400 }]\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP]\noperation = \"halt\"\nforms = [{ args = 1, kind = \"i\", opcode = 0 }]\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP]\nsize = 3\nforms = [{ args = 1, kind = \"i\", opcode = 0 }]\n").is_err());
+        assert!(InstructionSet::from_toml("[NOP]\nforms = [{ args = 1, kind = \"i\", opcode = 0 }]\n").is_ok());
+    }
+}
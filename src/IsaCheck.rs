@@ -0,0 +1,191 @@
+use std::fmt;
+use crate::Disassembler::decodes_as;
+use crate::InstructionParser::OPCODE_SHIFT;
+use crate::InstructionSet::{Constraint, FormKind, Instruction, InstructionSet, OperandForm};
+
+// One operand field of a form, letter is used for it in the bit pattern
+struct Field {
+    name: &'static str,
+    letter: char,
+    mask: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    // example is a word that both forms can produce
+    Collision { first: String, first_kind: FormKind, second: String, second_kind: FormKind, example: u32 },
+    // two operand fields of one form share bits
+    FieldOverlap { name: String, kind: FormKind, first: &'static str, second: &'static str, bits: u32 }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Collision { first, first_kind, second, second_kind, example } =>
+                write!(f, "{} '{}' and {} '{}' share encodings, for example {:#010X}", first, first_kind.name(), second, second_kind.name(), example),
+            Problem::FieldOverlap { name, kind, first, second, bits } =>
+                write!(f, "{} '{}': {} and {} share the bits {:#010X}", name, kind.name(), first, second, bits)
+        }
+    }
+}
+
+fn width(max: u32) -> u32 {
+    32 - max.leading_zeros()
+}
+
+pub fn immediate_mask(max: u32, shift: u32) -> u32 {
+    match width(max) {
+        0 => 0,
+        w => ((u64::MAX >> (64 - w)) << shift) as u32
+    }
+}
+
+fn form_fields(form: &OperandForm, constraint: &Constraint) -> Vec<Field> {
+    let target = Field { name: "target register", letter: 'T', mask: 0x3F << 16 };
+    let source_0 = Field { name: "source register 0", letter: 'S', mask: 0x3F << 10 };
+    let source_1 = Field { name: "source register 1", letter: 'R', mask: 0x3F << 4 };
+    let immediate_0 = |shift| Field { name: "immediate number 0", letter: 'I', mask: immediate_mask(constraint.immediate_0_number_max, shift) };
+    let immediate_1 = Field { name: "immediate number 1", letter: 'J', mask: immediate_mask(constraint.immediate_1_number_max, 4) };
+
+    return match form.kind {
+        FormKind::I => vec![immediate_0(0)],
+        FormKind::S => vec![target],
+        FormKind::Ss => vec![source_0, source_1],
+        FormKind::Ti => vec![target, immediate_0(0)],
+        FormKind::Ts => vec![target, source_0],
+        FormKind::Tsi => vec![target, source_0, immediate_0(0)],
+        FormKind::Tss => vec![target, source_0, source_1],
+        FormKind::Tii => vec![target, immediate_0(10), immediate_1]
+    }
+}
+
+// Bits that are the same in every word of the form, as (mask, value)
+fn fixed_bits(form: &OperandForm, constraint: &Constraint) -> (u32, u32) {
+    let free = form_fields(form, constraint).iter().fold(0, |m, f| m | f.mask);
+    (!free, (form.opcode << OPCODE_SHIFT) & !free)
+}
+
+// The opcode, then T/S/R for the target and source registers, I/J for the immediate
+// numbers, 0 for bits that are always zero and X where two fields share a bit
+pub fn bit_pattern(form: &OperandForm, constraint: &Constraint) -> String {
+    let fields = form_fields(form, constraint);
+    let mut result = String::new();
+    for bit in (0..32).rev() {
+        if bit == OPCODE_SHIFT - 1 {
+            result.push('_');
+        }
+
+        let owners = fields.iter().filter(|f| f.mask & (1 << bit) != 0).collect::<Vec<&Field>>();
+        result.push(match owners.len() {
+            0 if bit >= OPCODE_SHIFT => if form.opcode & (1 << (bit - OPCODE_SHIFT)) != 0 { '1' } else { '0' },
+            0 => '0',
+            1 => owners[0].letter,
+            _ => 'X'
+        });
+    }
+
+    result
+}
+
+pub fn check(isa: &InstructionSet) -> Vec<Problem> {
+    let mut result = vec![];
+    let forms = isa.instructions().iter().flat_map(|i| i.forms.iter().map(move |f| (i, f))).collect::<Vec<(&Instruction, &OperandForm)>>();
+
+    for (inst, form) in forms.iter() {
+        let fields = form_fields(form, &inst.constraint);
+        for (index, first) in fields.iter().enumerate() {
+            let opcode_bits = first.mask & (u32::MAX << OPCODE_SHIFT);
+            if opcode_bits != 0 {
+                result.push(Problem::FieldOverlap { name: inst.name.clone(), kind: form.kind, first: first.name, second: "opcode", bits: opcode_bits });
+            }
+            for second in fields[index + 1..].iter().filter(|f| f.mask & first.mask != 0) {
+                result.push(Problem::FieldOverlap { name: inst.name.clone(), kind: form.kind, first: first.name, second: second.name, bits: first.mask & second.mask });
+            }
+        }
+    }
+
+    for (index, (first, first_form)) in forms.iter().enumerate() {
+        let (first_mask, first_value) = fixed_bits(first_form, &first.constraint);
+        for (second, second_form) in forms[index + 1..].iter() {
+            let (second_mask, second_value) = fixed_bits(second_form, &second.constraint);
+            if (first_value ^ second_value) & first_mask & second_mask != 0 {
+                continue;
+            }
+
+            // every word that fits the fixed bits of both forms is tried, until one is
+            // valid for both, so register and immediate constraints are respected
+            let free = !(first_mask | second_mask);
+            let base = first_value | second_value;
+            let mut sub = 0_u32;
+            loop {
+                let word = base | sub;
+                if decodes_as(word, first_form, &first.constraint) && decodes_as(word, second_form, &second.constraint) {
+                    result.push(Problem::Collision {
+                        first: first.name.clone(),
+                        first_kind: first_form.kind,
+                        second: second.name.clone(),
+                        second_kind: second_form.kind,
+                        example: word
+                    });
+                    break;
+                }
+                // the next larger word, small operands are the likeliest to collide
+                sub = sub.wrapping_sub(free) & free;
+                if sub == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_pattern() {
+        let isa = InstructionSet::load(None).unwrap();
+        let load8 = isa.get("LOAD8").unwrap();
+        assert_eq!(bit_pattern(&load8.forms[0], &load8.constraint), "0000000001_TTTTTT00000000IIIIIIII");
+        let eq = isa.get("EQ").unwrap();
+        assert_eq!(bit_pattern(&eq.forms[0], &eq.constraint), "1001000011_TTTTTTSSSSSSRRRRRR0000");
+        let ojmp = isa.get("OJMP").unwrap();
+        assert_eq!(bit_pattern(&ojmp.forms[0], &ojmp.constraint), "1100000010_XXXXXXIIIIIIIIIIIIIIII");
+    }
+
+    #[test]
+    fn test_check_default_isa() {
+        let isa = InstructionSet::load(None).unwrap();
+        let problems = check(&isa);
+        let collisions = problems.iter().filter_map(|p| match p {
+            Problem::Collision { first, first_kind, second, second_kind, .. } => Some(format!("{} {} / {} {}", first, first_kind.name(), second, second_kind.name())),
+            _ => None
+        }).collect::<Vec<String>>();
+
+        assert!(collisions.contains(&String::from("ADD tss / SUB tss")));
+        assert!(collisions.contains(&String::from("LOAD32 ti / STORE8 ts")));
+        assert!(collisions.contains(&String::from("STORE8 ts / STORE8 tss")));
+        assert!(collisions.contains(&String::from("LOAD8 s / OJMP ti")));
+        // the s form can't have ZERO as target, so it never meets the ss form
+        assert!(!collisions.contains(&String::from("JMP s / JMP ss")));
+
+        assert!(problems.contains(&Problem::FieldOverlap { name: String::from("ZJMP"), kind: FormKind::Ti, first: "target register", second: "immediate number 0", bits: 0x3F0000 }));
+    }
+
+    #[test]
+    fn test_check_clean_isa() {
+        let isa = InstructionSet::from_toml(concat!(
+            "[ADD]\nimmediate_0_number_max = 0xFF\n",
+            "forms = [{ args = 3, kind = \"tss\", opcode = 1 }, { args = 3, kind = \"tsi\", opcode = 2 }]\n",
+            "[JMP]\ntarget_invalid_reg = [\"ZERO\"]\nimmediate_0_number_max = 0x3FFFFF\n",
+            "forms = [{ args = 1, kind = \"i\", opcode = 3 }, { args = 1, kind = \"s\", opcode = 4 }, { args = 2, kind = \"ss\", opcode = 4 }]\n"
+        )).unwrap();
+        assert_eq!(check(&isa), vec![]);
+
+        let isa = InstructionSet::from_toml("[BIG]\nimmediate_0_number_max = 0x7FFFFF\nforms = [{ args = 1, kind = \"i\", opcode = 3 }]\n").unwrap();
+        assert_eq!(check(&isa), vec![Problem::FieldOverlap { name: String::from("BIG"), kind: FormKind::I, first: "immediate number 0", second: "opcode", bits: 0x400000 }]);
+    }
+}
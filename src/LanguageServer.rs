@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use serde_json::{json, Value};
+use crate::Diagnostic::{Diagnostic, Severity};
+use crate::FileParser::{pars_source, strip_comment};
+use crate::InstructionParser::{get_register_name, pars_instructions};
+use crate::InstructionSet::InstructionSet;
+use crate::Preprocessor::Settings;
+use crate::Syntax;
+
+// LSP error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// LSP completion item kinds
+const KEYWORD: u32 = 14;
+const VARIABLE: u32 = 6;
+
+// A name written in the source, line and column start at 0 like in LSP
+#[derive(Debug, Clone, PartialEq)]
+struct Word {
+    text: String,
+    line: u64,
+    column: u64
+}
+
+// What the parse stages found out about one document
+struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    labels: HashMap<String, u64>,
+    // line (from 1) of every instruction, with its address and encoding once it assembled
+    instructions: HashMap<u64, (u64, Option<u32>)>
+}
+
+pub struct Server<'a> {
+    isa: &'a InstructionSet,
+    settings: Settings,
+    documents: HashMap<String, String>,
+    shutdown: bool
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// every name in the code of the text, strings and directives left out
+fn words(text: &str) -> Vec<Word> {
+    let mut result = vec![];
+    for (number, line) in text.split('\n').enumerate() {
+        let chars = strip_comment(line).chars().collect::<Vec<char>>();
+        let mut index = 0;
+        let mut in_string = false;
+        while index < chars.len() {
+            let c = chars[index];
+            if c == '\\' && in_string {
+                index += 2;
+                continue;
+            }
+            if c == '"' {
+                in_string = !in_string;
+            }
+            if in_string || !(c.is_ascii_alphabetic() || c == '_') || (index > 0 && (is_name_char(chars[index - 1]) || chars[index - 1] == '.')) {
+                index += 1;
+                continue;
+            }
+
+            let start = index;
+            while index < chars.len() && is_name_char(chars[index]) {
+                index += 1;
+            }
+            result.push(Word { text: chars[start..index].iter().collect(), line: number as u64, column: start as u64 });
+        }
+    }
+
+    result
+}
+
+// labels and data names with the place they are defined
+fn definitions(text: &str) -> HashMap<String, Word> {
+    let mut result = HashMap::new();
+    for (number, line) in text.split('\n').enumerate() {
+        let code = strip_comment(line);
+        let column = (code.len() - code.trim_start().len()) as u64;
+        let code = code.trim();
+        let (labels, rest) = Syntax::split_labels(code);
+        for (name, span) in labels {
+            result.entry(name.clone()).or_insert(Word { text: name, line: number as u64, column: column + span.start });
+        }
+
+        let rest = code.chars().skip(rest).collect::<String>();
+        let parts = rest.split_whitespace().collect::<Vec<&str>>();
+        let name = match parts.as_slice() {
+            [".VAR" | ".ARR", _, name, ..] => Some(*name),
+            [".STR", name, ..] => Some(*name),
+            _ => None
+        };
+
+        if let Some(name) = name {
+            let column = column + (code.len() - rest.len()) as u64 + rest.find(name).unwrap_or(0) as u64;
+            result.entry(name.to_string()).or_insert(Word { text: name.to_string(), line: number as u64, column });
+        }
+    }
+
+    result
+}
+
+fn word_at(text: &str, line: u64, column: u64) -> Option<Word> {
+    words(text).into_iter().find(|w| w.line == line && w.column <= column && column <= w.column + w.text.len() as u64)
+}
+
+fn range(line: u64, column: u64, length: u64) -> Value {
+    json!({ "start": { "line": line, "character": column }, "end": { "line": line, "character": column + length } })
+}
+
+fn location(uri: &str, word: &Word) -> Value {
+    json!({ "uri": uri, "range": range(word.line, word.column, word.text.len() as u64) })
+}
+
+// file:///a/b%20c.maasm -> /a/b c.maasm
+pub fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut result = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' && index + 2 < bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&path[index + 1..index + 3], 16) {
+                result.push(b);
+                index += 3;
+                continue;
+            }
+        }
+        result.push(bytes[index]);
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&result).to_string()
+}
+
+fn to_lsp(diagnostic: &Diagnostic) -> Value {
+    // our lines and columns start at 1, 0 is the whole file
+    let line = diagnostic.line.saturating_sub(1);
+    let column = diagnostic.column.saturating_sub(1);
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2
+    };
+    let mut message = diagnostic.message.clone();
+    for note in diagnostic.notes.iter() {
+        message += &format!("\nnote: {}", note);
+    }
+
+    json!({ "range": range(line, column, diagnostic.length.max(1)), "severity": severity, "source": "mycpuassembler", "message": message })
+}
+
+impl<'a> Server<'a> {
+    pub fn new(isa: &'a InstructionSet, settings: Settings) -> Server<'a> {
+        Server { isa, settings, documents: HashMap::new(), shutdown: false }
+    }
+
+    fn analyze(&self, uri: &str) -> Analysis {
+        let path = uri_to_path(uri);
+        let text = self.documents.get(uri).map(|t| t.as_str()).unwrap_or_default();
+        let mut analysis = Analysis { diagnostics: vec![], labels: HashMap::new(), instructions: HashMap::new() };
+
+        let asm_file = match pars_source(path.clone(), text, self.settings.clone()) {
+            Ok(f) => f,
+            Err(d) => {
+                analysis.diagnostics = d;
+                return analysis;
+            }
+        };
+        let symbols = asm_file.symbols();
+        analysis.diagnostics = asm_file.warnings;
+        analysis.labels = asm_file.labels.clone();
+
+        let lines = asm_file.instructions.iter().filter(|i| i.file == path).map(|i| (i.line, i.address)).collect::<Vec<(u64, u64)>>();
+        let image = match pars_instructions(asm_file.instructions, asm_file.data, symbols, self.isa) {
+            Ok(i) => Some(i),
+            Err(mut d) => {
+                analysis.diagnostics.append(&mut d);
+                None
+            }
+        };
+        for (line, address) in lines {
+            let word = image.as_ref().and_then(|i| i.bytes.get(address as usize..address as usize + 4)).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+            analysis.instructions.insert(line, (address, word));
+        }
+
+        analysis
+    }
+
+    fn publish(&self, uri: &str) -> Value {
+        let path = uri_to_path(uri);
+        let diagnostics = self.analyze(uri).diagnostics.iter().filter(|d| d.file == path).map(to_lsp).collect::<Vec<Value>>();
+        json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": { "uri": uri, "diagnostics": diagnostics } })
+    }
+
+    // The messages to send back, responses and notifications
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["%"] }
+                },
+                "serverInfo": { "name": "mycpuassembler" }
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            },
+            "textDocument/didOpen" => {
+                self.documents.insert(uri.clone(), params["textDocument"]["text"].as_str().unwrap_or_default().to_string());
+                return vec![self.publish(&uri)];
+            },
+            "textDocument/didChange" => {
+                // the server asks for full sync, so the last change is the whole text
+                if let Some(text) = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                return vec![self.publish(&uri)];
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": { "uri": uri, "diagnostics": [] } })];
+            },
+            "textDocument/definition" => self.definition(&uri, params),
+            "textDocument/references" => self.references(&uri, params),
+            "textDocument/hover" => self.hover(&uri, params),
+            "textDocument/completion" => self.completion(&uri, params),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'.", method)))
+        };
+
+        // notifications have no id and get no answer
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return vec![]
+        };
+        match result {
+            Ok(r) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": r })],
+            Err((code, e)) => vec![json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": e } })]
+        }
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown
+    }
+
+    fn position(&self, uri: &str, params: &Value) -> Result<(&str, u64, u64), (i64, String)> {
+        let text = match self.documents.get(uri) {
+            Some(t) => t.as_str(),
+            None => return Err((INVALID_PARAMS, format!("'{}' isn't open.", uri)))
+        };
+        match (params["position"]["line"].as_u64(), params["position"]["character"].as_u64()) {
+            (Some(line), Some(column)) => Ok((text, line, column)),
+            _ => Err((INVALID_PARAMS, String::from("The position is missing.")))
+        }
+    }
+
+    fn definition(&self, uri: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let (text, line, column) = self.position(uri, params)?;
+        let definition = word_at(text, line, column).and_then(|w| definitions(text).remove(&w.text));
+        Ok(match definition {
+            Some(d) => location(uri, &d),
+            None => Value::Null
+        })
+    }
+
+    fn references(&self, uri: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let (text, line, column) = self.position(uri, params)?;
+        let name = match word_at(text, line, column) {
+            Some(w) => w.text,
+            None => return Ok(json!([]))
+        };
+        let definitions = definitions(text);
+        let definition = match definitions.get(&name) {
+            Some(d) => d,
+            None => return Ok(json!([]))
+        };
+
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let result = words(text).into_iter()
+            .filter(|w| w.text == name && (include_declaration || w != definition))
+            .map(|w| location(uri, &w))
+            .collect::<Vec<Value>>();
+        Ok(Value::Array(result))
+    }
+
+    fn hover(&self, uri: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let (text, line, column) = self.position(uri, params)?;
+        let analysis = self.analyze(uri);
+
+        let word = word_at(text, line, column);
+        if let Some(word) = &word {
+            if let Some(address) = analysis.labels.get(&word.text) {
+                let contents = format!("`{}` at `{:#010X}`", word.text, address);
+                return Ok(json!({ "contents": { "kind": "markdown", "value": contents }, "range": range(word.line, word.column, word.text.len() as u64) }));
+            }
+        }
+
+        let contents = match analysis.instructions.get(&(line + 1)) {
+            Some((address, Some(encoding))) => format!("`{:#010X}`: `{:#010X}`\n\n`{:032b}`", address, encoding, encoding),
+            Some((address, None)) => format!("`{:#010X}`, doesn't assemble", address),
+            None => return Ok(Value::Null)
+        };
+        Ok(json!({ "contents": { "kind": "markdown", "value": contents } }))
+    }
+
+    fn completion(&self, uri: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let (text, line, column) = self.position(uri, params)?;
+        let before = text.split('\n').nth(line as usize).unwrap_or_default().chars().take(column as usize).collect::<String>();
+        let typed = before.chars().rev().take_while(|&c| is_name_char(c)).count();
+        let before = &before[..before.len() - typed];
+
+        if before.ends_with('%') {
+            let registers = (0..64).filter_map(get_register_name).map(|n| json!({ "label": n.to_lowercase(), "kind": VARIABLE })).collect::<Vec<Value>>();
+            return Ok(Value::Array(registers));
+        }
+        if before.trim().is_empty() {
+            let mnemonics = self.isa.instructions().iter().map(|i| {
+                let forms = i.forms.iter().map(|f| format!("{} ({} args)", f.kind.name(), f.args)).collect::<Vec<String>>().join(", ");
+                json!({ "label": i.name, "kind": KEYWORD, "detail": forms })
+            }).collect::<Vec<Value>>();
+            return Ok(Value::Array(mnemonics));
+        }
+
+        Ok(json!([]))
+    }
+}
+
+// Content-Length framed JSON-RPC on stdin and stdout, until the client sends exit
+pub fn serve(server: &mut Server, input: &mut impl BufRead, output: &mut impl Write) -> Result<(), String> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if input.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+                return Ok(());
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        let length = match length {
+            Some(l) => l,
+            None => return Err(String::from("A message without Content-Length."))
+        };
+        let mut body = vec![0; length];
+        input.read_exact(&mut body).map_err(|e| e.to_string())?;
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(m) => m,
+            Err(e) => return Err(e.to_string())
+        };
+
+        if message["method"] == "exit" {
+            return Ok(());
+        }
+        for reply in server.handle(&message) {
+            let reply = reply.to_string();
+            write!(output, "Content-Length: {}\r\n\r\n{}", reply.len(), reply).map_err(|e| e.to_string())?;
+            output.flush().map_err(|e| e.to_string())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = ".VAR BYTE count 3\nstart:\n    LOAD8 %a0, [1]\n    LOAD8 %a1, count ; count\n    JMP start\n    FOO %a0\n";
+
+    fn server(isa: &InstructionSet) -> Server<'_> {
+        let mut server = Server::new(isa, Settings { code_segment: 0x100, data_segment: 0x80, stack_segment: 0x1000, rom_size: None, include_paths: vec![] });
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": "file:///t.maasm", "text": SOURCE } } }));
+        server
+    }
+
+    fn request(server: &mut Server, method: &str, line: u64, character: u64) -> Value {
+        let params = json!({ "textDocument": { "uri": "file:///t.maasm" }, "position": { "line": line, "character": character }, "context": { "includeDeclaration": true } });
+        server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut server = Server::new(&isa, Settings { code_segment: 0, data_segment: 0x80, stack_segment: 0x1000, rom_size: None, include_paths: vec![] });
+        let replies = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": "file:///t.maasm", "text": SOURCE } } }));
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 5, "character": 4 }));
+        assert_eq!(diagnostics[0]["severity"], 1);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut server = server(&isa);
+
+        assert_eq!(request(&mut server, "textDocument/definition", 4, 9), json!({ "uri": "file:///t.maasm", "range": range(1, 0, 5) }));
+        assert_eq!(request(&mut server, "textDocument/definition", 3, 16), json!({ "uri": "file:///t.maasm", "range": range(0, 10, 5) }));
+        assert_eq!(request(&mut server, "textDocument/definition", 2, 13), Value::Null);
+
+        // the comment isn't a reference
+        let references = request(&mut server, "textDocument/references", 0, 12);
+        assert_eq!(references, json!([location("file:///t.maasm", &Word { text: String::from("count"), line: 0, column: 10 }), location("file:///t.maasm", &Word { text: String::from("count"), line: 3, column: 15 })]));
+    }
+
+    #[test]
+    fn test_escaped_quotes() {
+        let text = ".STR msg \"a\\\";b c\" ; d\n    LOAD8 %a0, msg\n";
+        assert_eq!(words(text).iter().map(|w| (w.text.as_str(), w.line)).collect::<Vec<(&str, u64)>>(), vec![("msg", 0), ("LOAD8", 1), ("a0", 1), ("msg", 1)]);
+        assert_eq!(definitions(text)["msg"], Word { text: String::from("msg"), line: 0, column: 5 });
+    }
+
+    #[test]
+    fn test_hover_and_completion() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut server = server(&isa);
+
+        // FOO doesn't assemble, so there is no encoding
+        assert_eq!(request(&mut server, "textDocument/hover", 4, 10)["contents"]["value"], "`start` at `0x00000100`");
+        assert_eq!(request(&mut server, "textDocument/hover", 2, 5)["contents"]["value"], "`0x00000100`, doesn't assemble");
+
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": "file:///t.maasm" }, "contentChanges": [{ "text": SOURCE.replace("    FOO %a0\n", "") }] } }));
+        assert_eq!(request(&mut server, "textDocument/hover", 2, 5)["contents"]["value"], "`0x00000100`: `0x00410001`\n\n`00000000010000010000000000000001`");
+
+        let registers = request(&mut server, "textDocument/completion", 2, 11);
+        assert!(registers.as_array().unwrap().contains(&json!({ "label": "a0", "kind": VARIABLE })));
+        let mnemonics = request(&mut server, "textDocument/completion", 2, 6);
+        assert_eq!(mnemonics[0]["label"], "LOAD8");
+        assert_eq!(request(&mut server, "textDocument/completion", 2, 14), json!([]));
+    }
+
+    #[test]
+    fn test_serve() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut server = Server::new(&isa, Settings { code_segment: 0, data_segment: 0x80, stack_segment: 0x1000, rom_size: None, include_paths: vec![] });
+        let messages = [json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }), json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }), json!({ "jsonrpc": "2.0", "method": "exit" })];
+        let input = messages.iter().map(|m| format!("Content-Length: {}\r\n\r\n{}", m.to_string().len(), m)).collect::<String>();
+
+        let mut output = vec![];
+        serve(&mut server, &mut input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Content-Length: "));
+        assert!(output.contains("\"hoverProvider\":true"));
+        assert!(output.ends_with("{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":null}"));
+        assert!(server.is_shut_down());
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use crate::FileParser::{Instr, Mark, MarkKind};
+use crate::InstructionParser::Image;
+
+// read_source gives the text of a source file, lines that can't be read fall back to the parsed text
+pub fn to_listing(instructions: &[Instr], marks: &[Mark], image: &Image, read_source: impl Fn(&str) -> Option<String>) -> String {
+    let mut sources: HashMap<String, Option<Vec<String>>> = HashMap::new();
+    let mut source_line = |file: &str, line: u64, fallback: String| -> String {
+        let lines = sources.entry(file.to_string()).or_insert_with(|| {
+            read_source(file).map(|s| s.split('\n').map(|l| l.trim_end_matches('\r').to_string()).collect())
+        });
+        match lines.as_ref().and_then(|l| l.get(line.wrapping_sub(1) as usize)) {
+            Some(l) => l.clone(),
+            None => fallback
+        }
+    };
+
+    let mut result = String::from("ADDRESS   WORD      LINE  SOURCE\n");
+    let mut marks = marks.iter().peekable();
+    for (index, instr) in instructions.iter().enumerate() {
+        while let Some(mark) = marks.next_if(|m| m.index <= index) {
+            // a label in front of the instruction is shown with it
+            if matches!(mark.kind, MarkKind::Label(_)) && mark.file == instr.file && mark.line == instr.line {
+                continue;
+            }
+            result += &mark_line(mark, &mut source_line);
+        }
+
+        let word = match image.bytes.get(instr.address as usize..instr.address as usize + 4) {
+            Some(b) => format!("{:08X}", u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            None => String::from("????????")
+        };
+        let source = source_line(&instr.file, instr.line, instr.data.clone());
+        result += &format!("{:08X}  {}  {:>4}  {}\n", instr.address, word, instr.line, source);
+    }
+    for mark in marks {
+        result += &mark_line(mark, &mut source_line);
+    }
+
+    result
+}
+
+fn mark_line(mark: &Mark, source_line: &mut impl FnMut(&str, u64, String) -> String) -> String {
+    let fallback = match &mark.kind {
+        MarkKind::Label(name) => format!("{}:", name),
+        MarkKind::At => format!(".AT {:#X}", mark.address)
+    };
+    let source = source_line(&mark.file, mark.line, fallback);
+    match mark.kind {
+        MarkKind::Label(_) => format!("{:08X}            {:>4}  {}\n", mark.address, mark.line, source),
+        MarkKind::At => format!("\n{:08X}            {:>4}  {}\n", mark.address, mark.line, source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instr(data: &str, address: u64, line: u64) -> Instr {
+        Instr { data: data.to_string(), statement: crate::Syntax::parse_statement(data).unwrap(), address, file: String::from("test.maasm"), line, column: 5, scope: Default::default(), included_from: vec![], replacements: vec![] }
+    }
+
+    fn mark(kind: MarkKind, address: u64, index: usize, line: u64) -> Mark {
+        Mark { kind, address, index, file: String::from("test.maasm"), line }
+    }
+
+    #[test]
+    fn test_to_listing() {
+        let mut image = Image::new();
+        image.bytes = vec![0, 0, 0, 0, 0x04, 0x00, 0x0F, 0xC0, 0x00, 0x00, 0x41, 0x00];
+        let instructions = vec![instr("JMP start", 4, 3), instr("LOAD8 %a0, [0]", 8, 5)];
+        let marks = vec![
+            mark(MarkKind::At, 4, 0, 1),
+            mark(MarkKind::Label(String::from("start")), 4, 0, 2),
+            mark(MarkKind::Label(String::from("end")), 12, 2, 6)
+        ];
+
+        let source = ".AT 4\nstart:\n    JMP start\n; comment\n\tLOAD8 %a0, [0]\nend:";
+        assert_eq!(to_listing(&instructions, &marks, &image, |_| Some(source.to_string())), concat!(
+            "ADDRESS   WORD      LINE  SOURCE\n",
+            "\n",
+            "00000004               1  .AT 4\n",
+            "00000004               2  start:\n",
+            "00000004  C00F0004     3      JMP start\n",
+            "00000008  00410000     5  \tLOAD8 %a0, [0]\n",
+            "0000000C               6  end:\n"
+        ));
+
+        assert_eq!(to_listing(&instructions[..1], &marks[..1], &image, |_| None), concat!(
+            "ADDRESS   WORD      LINE  SOURCE\n",
+            "\n",
+            "00000004               1  .AT 0x4\n",
+            "00000004  C00F0004     3  JMP start\n"
+        ));
+    }
+}
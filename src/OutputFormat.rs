@@ -0,0 +1,347 @@
+use crate::InstructionParser::Image;
+
+const IHEX_RECORD_SIZE: u64 = 16;
+
+fn ihex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, record_type];
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+    bytes.push(checksum);
+
+    let mut record = String::from(":");
+    for b in bytes {
+        record += &format!("{:02X}", b);
+    }
+    record + "\n"
+}
+
+// Only the written segments of the image are emitted. An extended linear address
+// record (type 04) is written whenever the upper 16 bits of the address change,
+// and no data record crosses a 64 KiB boundary.
+pub fn to_ihex(image: &Image, entry: Option<u64>) -> Result<String, String> {
+    let mut result = String::new();
+    let mut upper_address = 0;
+
+    for &(start, end) in image.segments.iter() {
+        if end > 0x1_0000_0000 {
+            return Err(format!("address {:#X} doesn't fit in an Intel HEX file.", end - 1));
+        }
+
+        let mut address = start;
+        while address < end {
+            if address >> 16 != upper_address {
+                upper_address = address >> 16;
+                result += &ihex_record(0, 0x04, &(upper_address as u16).to_be_bytes());
+            }
+
+            let boundary = (address | 0xFFFF) + 1;
+            let record_end = end.min(address + IHEX_RECORD_SIZE).min(boundary);
+            result += &ihex_record(address as u16, 0x00, &image.bytes[address as usize..record_end as usize]);
+            address = record_end;
+        }
+    }
+
+    if let Some(entry) = entry {
+        match u32::try_from(entry) {
+            Ok(e) => result += &ihex_record(0, 0x05, &e.to_be_bytes()),
+            Err(_) => return Err(format!("entry address {:#X} doesn't fit in an Intel HEX file.", entry))
+        }
+    }
+
+    result += &ihex_record(0, 0x01, &[]);
+    Ok(result)
+}
+
+const SREC_RECORD_SIZE: u64 = 16;
+
+fn srec_record(record_type: u8, address: u64, address_size: usize, data: &[u8]) -> String {
+    let mut bytes = vec![(address_size + data.len() + 1) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes()[8 - address_size..]);
+    bytes.extend_from_slice(data);
+
+    let checksum = !bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(checksum);
+
+    let mut record = format!("S{}", record_type);
+    for b in bytes {
+        record += &format!("{:02X}", b);
+    }
+    record + "\n"
+}
+
+// The address width is the smallest one that holds the highest written address and the entry:
+// S1/S9 for 16 bits, S2/S8 for 24 bits and S3/S7 for 32 bits.
+pub fn to_srec(image: &Image, entry: Option<u64>, header: &str) -> Result<String, String> {
+    let highest = image.segments.iter().map(|s| s.1.saturating_sub(1)).max().unwrap_or(0).max(entry.unwrap_or(0));
+    let (data_type, end_type, address_size) = if highest <= 0xFFFF {
+        (1, 9, 2)
+    } else if highest <= 0xFF_FFFF {
+        (2, 8, 3)
+    } else if highest <= 0xFFFF_FFFF {
+        (3, 7, 4)
+    } else {
+        return Err(format!("address {:#X} doesn't fit in an S-record file.", highest));
+    };
+
+    // the header record holds at most 64 bytes of text
+    let header = &header.as_bytes()[..header.len().min(64)];
+    let mut result = srec_record(0, 0, 2, header);
+    let mut count = 0_u64;
+    for &(start, end) in image.segments.iter() {
+        let mut address = start;
+        while address < end {
+            let record_end = end.min(address + SREC_RECORD_SIZE);
+            result += &srec_record(data_type, address, address_size, &image.bytes[address as usize..record_end as usize]);
+            count += 1;
+            address = record_end;
+        }
+    }
+
+    if count <= 0xFFFF {
+        result += &srec_record(5, count, 2, &[]);
+    } else if count <= 0xFF_FFFF {
+        result += &srec_record(6, count, 3, &[]);
+    }
+    result += &srec_record(end_type, entry.unwrap_or(0), address_size, &[]);
+
+    Ok(result)
+}
+
+// Segments widened to whole words of word_size bytes, merged again if they touch after that.
+fn word_segments(image: &Image, word_size: u64) -> Vec<(u64, u64)> {
+    let mut result: Vec<(u64, u64)> = vec![];
+    for &(start, end) in image.segments.iter() {
+        let start = start / word_size * word_size;
+        let end = end.div_ceil(word_size) * word_size;
+        match result.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => result.push((start, end))
+        }
+    }
+
+    result
+}
+
+// Words are read little endian from the image, so with 32 bit words every line is one instruction.
+// Every segment starts with an @ marker holding its word address.
+pub fn to_readmem(image: &Image, word_width: u32, binary: bool) -> Result<String, String> {
+    if ![8, 16, 32].contains(&word_width) {
+        return Err(format!("word width must be 8, 16 or 32, not {}.", word_width));
+    }
+
+    let word_size = (word_width / 8) as u64;
+    let mut result = String::new();
+    for (start, end) in word_segments(image, word_size) {
+        result += &format!("@{:X}\n", start / word_size);
+
+        for address in (start..end).step_by(word_size as usize) {
+            let mut word = 0_u32;
+            for i in (0..word_size).rev() {
+                word = (word << 8) | *image.bytes.get((address + i) as usize).unwrap_or(&0) as u32;
+            }
+
+            if binary {
+                result += &format!("{:0width$b}\n", word, width = word_width as usize);
+            } else {
+                result += &format!("{:0width$X}\n", word, width = word_width as usize / 4);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// The ROM holds rom_size bytes from the first instruction rounded down to a multiple of
+// rom_size. Data outside of it stays out of the ROM, code outside of it is an error.
+fn rom_words(image: &Image, word_width: u32, rom_size: Option<u64>) -> Result<Vec<u32>, String> {
+    if ![8, 16, 32].contains(&word_width) {
+        return Err(format!("word width must be 8, 16 or 32, not {}.", word_width));
+    }
+
+    let rom_size = match rom_size {
+        Some(r) => r,
+        None => return Err(String::from("the depth of the memory is unknown, use --rom-size or .SET ROMSIZE."))
+    };
+
+    let word_size = (word_width / 8) as u64;
+    if rom_size == 0 || rom_size % word_size != 0 {
+        return Err(format!("ROM size {:#X} isn't a whole number of {} bit words.", rom_size, word_width));
+    }
+
+    let base = image.code.first().map(|c| c.0 / rom_size * rom_size).unwrap_or(0);
+    let rom_end = base + rom_size;
+    if let Some((start, end)) = image.code.iter().find(|c| c.1 > rom_end) {
+        return Err(format!("code at {:#X}..{:#X} is outside the ROM at {:#X}..{:#X}.", start, end, base, rom_end));
+    }
+
+    let mut words = vec![0_u32; (rom_size / word_size) as usize];
+    for (start, end) in word_segments(image, word_size) {
+        for address in (start.max(base)..end.min(rom_end)).step_by(word_size as usize) {
+            let index = ((address - base) / word_size) as usize;
+            for i in (0..word_size).rev() {
+                words[index] = (words[index] << 8) | *image.bytes.get((address + i) as usize).unwrap_or(&0) as u32;
+            }
+        }
+    }
+
+    Ok(words)
+}
+
+fn format_word(word: u32, word_width: u32) -> String {
+    format!("{:0width$X}", word, width = word_width as usize / 4)
+}
+
+// Xilinx coefficient file for block memory generator
+pub fn to_coe(image: &Image, word_width: u32, rom_size: Option<u64>) -> Result<String, String> {
+    let words = rom_words(image, word_width, rom_size)?;
+
+    let mut result = String::from("memory_initialization_radix=16;\nmemory_initialization_vector=\n");
+    for (i, word) in words.iter().enumerate() {
+        let end = if i + 1 == words.len() { ";" } else { "," };
+        result += &format!("{}{}\n", format_word(*word, word_width), end);
+    }
+
+    Ok(result)
+}
+
+// Intel/Altera memory initialization file, runs of the same word are written as one address range
+pub fn to_mif(image: &Image, word_width: u32, rom_size: Option<u64>) -> Result<String, String> {
+    let words = rom_words(image, word_width, rom_size)?;
+
+    let mut result = format!("WIDTH={};\nDEPTH={};\n\nADDRESS_RADIX=HEX;\nDATA_RADIX=HEX;\n\nCONTENT BEGIN\n", word_width, words.len());
+    let mut start = 0;
+    while start < words.len() {
+        let mut end = start;
+        while end + 1 < words.len() && words[end + 1] == words[start] {
+            end += 1;
+        }
+
+        if start == end {
+            result += &format!("\t{:X} : {};\n", start, format_word(words[start], word_width));
+        } else {
+            result += &format!("\t[{:X}..{:X}] : {};\n", start, end, format_word(words[start], word_width));
+        }
+        start = end + 1;
+    }
+    result += "END;\n";
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(segments: &[(u64, &[u8])]) -> Image {
+        let end = segments.iter().map(|(a, d)| a + d.len() as u64).max().unwrap_or(0);
+        let mut bytes = vec![0; end as usize];
+        for (address, data) in segments {
+            bytes[*address as usize..*address as usize + data.len()].copy_from_slice(data);
+        }
+        let segments = segments.iter().map(|(a, d)| (*a, a + d.len() as u64)).collect::<Vec<(u64, u64)>>();
+        Image { bytes, code: segments.clone(), segments }
+    }
+
+    #[test]
+    fn test_ihex_record() {
+        assert_eq!(ihex_record(0, 0x01, &[]), ":00000001FF\n");
+        assert_eq!(ihex_record(0, 0x04, &[0x00, 0x0F]), ":02000004000FEB\n");
+        assert_eq!(ihex_record(0x0030, 0x00, &[0x02, 0x33, 0x7A]), ":0300300002337A1E\n");
+    }
+
+    #[test]
+    fn test_to_ihex() {
+        let data = (0..18).collect::<Vec<u8>>();
+        let result = to_ihex(&image(&[(0x0, &[0xAA]), (0x10, &data), (0xFFFFC, &data[..8])]), None).unwrap();
+        assert_eq!(result, concat!(
+            ":01000000AA55\n",
+            ":10001000000102030405060708090A0B0C0D0E0F68\n",
+            ":020020001011BD\n",
+            ":02000004000FEB\n",
+            ":04FFFC0000010203FB\n",
+            ":020000040010EA\n",
+            ":0400000004050607E6\n",
+            ":00000001FF\n"
+        ));
+
+        assert!(to_ihex(&Image { bytes: vec![], segments: vec![(0xFFFFFFFF, 0x100000001)], code: vec![] }, None).is_err());
+
+        let result = to_ihex(&image(&[(0x0, &[0xAA])]), Some(0xF0000)).unwrap();
+        assert_eq!(result, ":01000000AA55\n:04000005000F0000E8\n:00000001FF\n");
+    }
+
+    #[test]
+    fn test_srec_record() {
+        assert_eq!(srec_record(0, 0, 2, b"HDR"), "S00600004844521B\n");
+        assert_eq!(srec_record(1, 0x0038, 2, &[0x48, 0x65, 0x6C, 0x6C, 0x6F]), "S108003848656C6C6FCB\n");
+        assert_eq!(srec_record(9, 0, 2, &[]), "S9030000FC\n");
+    }
+
+    #[test]
+    fn test_to_srec_address_width() {
+        let result = to_srec(&image(&[(0x10, &[1, 2])]), None, "").unwrap();
+        assert_eq!(result, "S0030000FC\nS10500100102E7\nS5030001FB\nS9030000FC\n");
+
+        let result = to_srec(&image(&[(0x10, &[1, 2])]), Some(0xF0000), "").unwrap();
+        assert_eq!(result, "S0030000FC\nS2060000100102E6\nS5030001FB\nS8040F0000EC\n");
+
+        let result = to_srec(&image(&[(0xFFFFFC, &[1, 2, 3, 4, 5])]), None, "").unwrap();
+        assert_eq!(result, "S0030000FC\nS30A00FFFFFC0102030405EC\nS5030001FB\nS70500000000FA\n");
+
+        assert!(to_srec(&image(&[(0x10, &[1])]), Some(0x1_0000_0000), "").is_err());
+    }
+
+    #[test]
+    fn test_to_readmem() {
+        let image = image(&[(0x0, &[0x04, 0x00, 0x0F, 0xC0]), (0x4, &[0x01]), (0x12, &[0xAB, 0xCD])]);
+
+        assert_eq!(to_readmem(&image, 32, false).unwrap(), "@0\nC00F0004\n00000001\n@4\nCDAB0000\n");
+        assert_eq!(to_readmem(&image, 16, false).unwrap(), "@0\n0004\nC00F\n0001\n@9\nCDAB\n");
+        assert_eq!(to_readmem(&image, 8, false).unwrap(), "@0\n04\n00\n0F\nC0\n01\n@12\nAB\nCD\n");
+        assert_eq!(to_readmem(&image, 8, true).unwrap(), concat!(
+            "@0\n00000100\n00000000\n00001111\n11000000\n00000001\n",
+            "@12\n10101011\n11001101\n"
+        ));
+        assert!(to_readmem(&image, 24, false).is_err());
+    }
+
+    #[test]
+    fn test_to_coe() {
+        let mut image = image(&[(0x2000, &[0xAA]), (0xFFFF0, &[0x04, 0x00, 0x0F, 0xC0]), (0xFFFF8, &[0x50, 0x00, 0x3F, 0xC0])]);
+        // the data at 0x2000 is in RAM
+        image.code.remove(0);
+
+        assert_eq!(to_coe(&image, 32, Some(16)).unwrap(), concat!(
+            "memory_initialization_radix=16;\n",
+            "memory_initialization_vector=\n",
+            "C00F0004,\n",
+            "00000000,\n",
+            "C03F0050,\n",
+            "00000000;\n"
+        ));
+        assert!(to_coe(&image, 32, None).is_err());
+        assert!(to_coe(&image, 32, Some(6)).is_err());
+        assert_eq!(to_coe(&image, 32, Some(8)).unwrap_err(), "code at 0xFFFF8..0xFFFFC is outside the ROM at 0xFFFF0..0xFFFF8.");
+    }
+
+    #[test]
+    fn test_to_mif() {
+        let image = image(&[(0x0, &[0x04, 0x00]), (0xA, &[0x01, 0x00])]);
+
+        assert_eq!(to_mif(&image, 16, Some(16)).unwrap(), concat!(
+            "WIDTH=16;\n",
+            "DEPTH=8;\n",
+            "\n",
+            "ADDRESS_RADIX=HEX;\n",
+            "DATA_RADIX=HEX;\n",
+            "\n",
+            "CONTENT BEGIN\n",
+            "\t0 : 0004;\n",
+            "\t[1..4] : 0000;\n",
+            "\t5 : 0001;\n",
+            "\t[6..7] : 0000;\n",
+            "END;\n"
+        ));
+    }
+}
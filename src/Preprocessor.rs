@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use crate::Diagnostic::Diagnostic;
+use crate::FileParser::SourceLine;
+use crate::Syntax::{self, Statement, Token, TokenKind};
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub code_segment: u64,
+    pub data_segment: u64,
+    pub stack_segment: u64,
+    pub rom_size: Option<u64>,
+    // where .INCLUDE looks for files that aren't next to the including file
+    pub include_paths: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub name: String,
+    pub address: u64,
+    pub bytes: Vec<u8>,
+    pub file: String,
+    pub line: u64,
+    // where the directive is, for address conflicts
+    pub column: u64,
+    pub length: u64,
+    pub included_from: Vec<(String, u64)>,
+    // .INCBIN bytes are placed between the instructions, .VAR, .ARR and .STR in the data segment
+    pub code: bool
+}
+
+// A .DEF value put into a line: where it starts in the new text, its length and the length
+// of the name it replaced, in characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Replacement {
+    pub start: u64,
+    pub length: u64,
+    pub name_length: u64
+}
+
+pub struct Preprocessed {
+    pub lines: Vec<SourceLine>,
+    pub settings: Settings,
+    pub data: Vec<Data>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataType {
+    Byte,
+    Word,
+    Dword
+}
+
+impl DataType {
+    fn from_name(name: &str) -> Option<DataType> {
+        return match name.to_lowercase().as_str() {
+            "byte" => Some(DataType::Byte),
+            "word" => Some(DataType::Word),
+            "dword" => Some(DataType::Dword),
+            _ => None
+        }
+    }
+
+    fn size(&self) -> u64 {
+        return match self {
+            DataType::Byte => 1,
+            DataType::Word => 2,
+            DataType::Dword => 4
+        }
+    }
+
+    fn to_bytes(self, value: u64) -> Result<Vec<u8>, String> {
+        let max = (1_u64 << (self.size() * 8)) - 1;
+        if value > max {
+            return Err(format!("{} doesn't fit in {:?}.", value, self));
+        }
+        Ok(value.to_le_bytes()[..self.size() as usize].to_vec())
+    }
+}
+
+// Handles the directives that have to be known before the code is laid out.
+// .DEF, .VAR, .ARR and .STR names are collected first, .DEF replacements are applied to
+// every line, then .SET, then .VAR/.STR/.ARR are placed in the data segment.
+// .AT and everything else is left for pars_file.
+pub fn preprocess(lines: Vec<SourceLine>, settings: Settings) -> Result<Preprocessed, Vec<Diagnostic>> {
+    // the ROM size from the command line wins over .SET ROMSIZE
+    let rom_size = settings.rom_size;
+    let mut settings = settings;
+    // the index of the line with every error, they are found in several passes
+    let mut diagnostics: Vec<(usize, Diagnostic)> = vec![];
+    let mut names: HashMap<String, (String, u64)> = HashMap::new();
+
+    let mut defines: Vec<(String, String)> = vec![];
+    let mut rest = vec![];
+    for (index, line) in lines.into_iter().enumerate() {
+        let (directive, arguments) = match directive(&line.text) {
+            Some(d) => d,
+            None => {
+                rest.push((index, line));
+                continue;
+            }
+        };
+        let name = match defined_name(&directive, &arguments) {
+            Some(n) => n,
+            None => {
+                rest.push((index, line));
+                continue;
+            }
+        };
+        if name.is_empty() && directive == "DEF" {
+            diagnostics.push((index, line_error(&line, String::from(".DEF: missing name."))));
+            continue;
+        }
+        // an empty data name is reported with the rest of the directive
+        if !name.is_empty() {
+            if let Err(e) = check_name(&name, &mut names, &line) {
+                diagnostics.push((index, line_error(&line, e)));
+                continue;
+            }
+        }
+
+        if directive == "DEF" {
+            let value = replace_defines(split_first_word(&arguments).1, &defines).0;
+            defines.push((name, value));
+        } else {
+            rest.push((index, line));
+        }
+    }
+
+    let lines = rest
+        .into_iter()
+        .map(|(index, line)| {
+            let (text, replacements) = replace_defines(&line.text, &defines);
+            (index, SourceLine { text, replacements, ..line })
+        })
+        .collect::<Vec<(usize, SourceLine)>>();
+
+    let mut rest = vec![];
+    for (index, line) in lines {
+        let arguments = match directive(&line.text) {
+            Some((d, a)) if d == "SET" => a,
+            _ => {
+                rest.push((index, line));
+                continue;
+            }
+        };
+
+        let (key, value) = split_first_word(&arguments);
+        let value = match pars_value(value) {
+            Ok(v) => v,
+            Err(e) => {
+                diagnostics.push((index, line_error(&line, format!(".SET {}: {}", key, e))));
+                continue;
+            }
+        };
+        match key.to_uppercase().as_str() {
+            "CODESEGMENT" => settings.code_segment = value,
+            "DATASEGMENT" => settings.data_segment = value,
+            "STACKSEGMENT" => settings.stack_segment = value,
+            "ROMSIZE" => settings.rom_size = rom_size.or(Some(value)),
+            _ => diagnostics.push((index, line_error(&line, format!(".SET: unknown setting '{}'.", key))))
+        }
+    }
+
+    let mut data = vec![];
+    let mut data_counter = settings.data_segment;
+    let mut result = vec![];
+    for (index, line) in rest {
+        let (name, data_type, bytes) = match directive(&line.text).and_then(|(d, a)| pars_data(&d, &a)) {
+            Some(Ok(d)) => d,
+            Some(Err(e)) => {
+                diagnostics.push((index, line_error(&line, e)));
+                continue;
+            },
+            None => {
+                result.push(line);
+                continue;
+            }
+        };
+
+        // every item is aligned to the size of its elements
+        let align = data_type.size();
+        data_counter = data_counter.div_ceil(align) * align;
+        data.push(Data {
+            name,
+            address: data_counter,
+            bytes: bytes.clone(),
+            file: line.file.clone(),
+            line: line.line,
+            column: line.column,
+            length: line.length(),
+            included_from: line.included_from.clone(),
+            code: false
+        });
+        data_counter += bytes.len() as u64;
+    }
+
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| d.0);
+        return Err(diagnostics.into_iter().map(|d| d.1).collect());
+    }
+
+    Ok(Preprocessed { lines: result, settings, data })
+}
+
+// The upper case name and the arguments of a directive line
+fn directive(line: &str) -> Option<(String, String)> {
+    match Syntax::parse_statement(line) {
+        Ok(Statement::Directive { name, arguments, .. }) => Some((name, arguments)),
+        _ => None
+    }
+}
+
+// The name a .DEF, .VAR, .ARR or .STR directive defines, as it is written
+fn defined_name(directive: &str, arguments: &str) -> Option<String> {
+    let (first, rest) = split_first_word(arguments);
+    match directive {
+        "DEF" | "STR" => Some(first.to_string()),
+        "VAR" | "ARR" => match DataType::from_name(first) {
+            Some(_) => Some(split_first_word(rest).0.to_string()),
+            None => Some(first.to_string())
+        },
+        _ => None
+    }
+}
+
+// name, element type and content of a .VAR, .ARR or .STR directive
+type DataDirective = (String, DataType, Vec<u8>);
+
+// None when the directive isn't .VAR, .ARR or .STR
+fn pars_data(directive: &str, arguments: &str) -> Option<Result<DataDirective, String>> {
+    match directive {
+        "VAR" => Some(split_typed(arguments).and_then(|(data_type, name, value)| {
+            let value = match pars_value(value) {
+                Ok(v) => v,
+                Err(e) => return Err(format!(".VAR {}: {}", name, e))
+            };
+            match data_type.to_bytes(value) {
+                Ok(b) => Ok((name, data_type, b)),
+                Err(e) => Err(format!(".VAR {}: {}", name, e))
+            }
+        })),
+        "ARR" => Some(split_typed(arguments).and_then(|(data_type, name, values)| {
+            let mut bytes = vec![];
+            for value in values.split(',') {
+                let value = match pars_value(value) {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!(".ARR {}: {}", name, e))
+                };
+                match data_type.to_bytes(value) {
+                    Ok(mut b) => bytes.append(&mut b),
+                    Err(e) => return Err(format!(".ARR {}: {}", name, e))
+                }
+            }
+            Ok((name, data_type, bytes))
+        })),
+        "STR" => {
+            let (name, value) = split_first_word(arguments);
+            let string = match Syntax::tokenize(value).as_deref() {
+                Ok([Token { kind: TokenKind::String(s), .. }]) => pars_string(s),
+                _ => Err(String::from("string must be quoted with '\"'."))
+            };
+            Some(match string {
+                Ok(mut bytes) => {
+                    bytes.push(0);
+                    Ok((name.to_string(), DataType::Byte, bytes))
+                },
+                Err(e) => Err(format!(".STR {}: {}", name, e))
+            })
+        },
+        _ => None
+    }
+}
+
+// A number in any of the spellings pars_number knows, or an expression of numbers
+pub fn pars_value(value: &str) -> Result<u64, String> {
+    if let Ok(v) = pars_number(value) {
+        return Ok(v);
+    }
+    match Syntax::parse_expression(value) {
+        Ok(expr) => expr.evaluate(&HashMap::new()).map(u64::from),
+        Err(e) => Err(e.message)
+    }
+}
+
+pub fn pars_number(number: &str) -> Result<u64, String> {
+    let number = number.trim();
+    let (digits, radix) = if let Some(d) = number.strip_prefix("0x").or(number.strip_prefix("hex")) {
+        (d, 16)
+    } else if let Some(d) = number.strip_prefix("0o").or(number.strip_prefix("oct")) {
+        (d, 8)
+    } else if let Some(d) = number.strip_prefix("0b").or(number.strip_prefix("bin")) {
+        (d, 2)
+    } else {
+        (number, 10)
+    };
+
+    match u64::from_str_radix(&digits.replace('_', ""), radix) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("Invalid number '{}': {}", number, e))
+    }
+}
+
+// The text between the quotes of a string token with its escapes resolved
+fn pars_string(string: &str) -> Result<Vec<u8>, String> {
+    let mut result = vec![];
+    let mut chars = string.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(o) => return Err(format!("unknown escape sequence '\\{}'.", o)),
+                None => return Err(String::from("unfinished escape sequence."))
+            }
+        } else {
+            c
+        };
+
+        let mut buffer = [0; 4];
+        result.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+
+    Ok(result)
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false
+    }
+}
+
+fn check_name(name: &str, names: &mut HashMap<String, (String, u64)>, line: &SourceLine) -> Result<(), String> {
+    if !is_valid_name(name) {
+        return Err(format!("'{}' is not a valid name.", name));
+    }
+    if let Some((file, first)) = names.get(name) {
+        return Err(format!("'{}' is already defined at {}:{}.", name, file, first));
+    }
+    names.insert(name.to_string(), (line.file.clone(), line.line));
+    Ok(())
+}
+
+fn line_error(line: &SourceLine, message: String) -> Diagnostic {
+    line.error(0, message).with_length(line.length())
+}
+
+fn split_first_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((w, r)) => (w, r.trim()),
+        None => (text, "")
+    }
+}
+
+// "[type] NAME value", the type is dword when it is omitted
+fn split_typed(text: &str) -> Result<(DataType, String, &str), String> {
+    let (first, rest) = split_first_word(text);
+    let (data_type, name, value) = match DataType::from_name(first) {
+        Some(t) => {
+            let (name, value) = split_first_word(rest);
+            (t, name, value)
+        },
+        None => (DataType::Dword, first, rest)
+    };
+
+    if value.is_empty() {
+        return Err(format!("{}: missing value.", name));
+    }
+
+    Ok((data_type, name.to_string(), value))
+}
+
+// The line with every defined name replaced by its value, and where the values are
+fn replace_defines(line: &str, defines: &[(String, String)]) -> (String, Vec<Replacement>) {
+    if defines.is_empty() {
+        return (line.to_string(), vec![]);
+    }
+
+    let mut result = String::new();
+    let mut replacements = vec![];
+    let mut length = 0;
+    let mut word = String::new();
+    let mut in_string = false;
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if !in_string && (c.is_ascii_alphanumeric() || c == '_') {
+            word.push(c);
+            continue;
+        }
+
+        if !word.is_empty() {
+            let name_length = word.chars().count() as u64;
+            match defines.iter().find(|(n, _)| *n == word) {
+                Some((_, v)) => {
+                    let value_length = v.chars().count() as u64;
+                    replacements.push(Replacement { start: length, length: value_length, name_length });
+                    result.push_str(v);
+                    length += value_length;
+                },
+                None => {
+                    result.push_str(&word);
+                    length += name_length;
+                }
+            }
+            word.clear();
+        }
+
+        if c == '"' {
+            in_string = !in_string;
+        }
+        result.push(c);
+        length += 1;
+    }
+
+    result.pop();
+    (result, replacements)
+}
+
+// Where an offset into a line after .DEF replacements is in the line as it was written. An end
+// inside a value is moved to the end of the name, a start to its beginning.
+fn original_offset(replacements: &[Replacement], offset: u64, is_end: bool) -> u64 {
+    let mut shift = 0_i64;
+    for r in replacements {
+        if offset < r.start || (is_end && offset == r.start) {
+            break;
+        }
+        if offset < r.start + r.length {
+            let start = r.start as i64 + shift;
+            return if is_end { (start + r.name_length as i64) as u64 } else { start as u64 };
+        }
+        shift += r.name_length as i64 - r.length as i64;
+    }
+    (offset as i64 + shift) as u64
+}
+
+// start and length of a span of a line after .DEF replacements, in the line as it was written
+pub fn original_span(replacements: &[Replacement], start: u64, length: u64) -> (u64, u64) {
+    let original_start = original_offset(replacements, start, false);
+    let original_end = original_offset(replacements, start + length, true);
+    (original_start, original_end.saturating_sub(original_start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings { code_segment: 0, data_segment: 0x2000, stack_segment: 0x1000, rom_size: None, include_paths: vec![] }
+    }
+
+    fn lines(source: &[&str]) -> Vec<SourceLine> {
+        source.iter().enumerate().map(|(n, l)| SourceLine { text: l.to_string(), file: String::from("test.maasm"), line: n as u64 + 1, column: 1, included_from: vec![], replacements: vec![] }).collect()
+    }
+
+    #[test]
+    fn test_pars_number() {
+        assert_eq!(pars_number("hex1000"), Ok(0x1000));
+        assert_eq!(pars_number("0x1000"), Ok(0x1000));
+        assert_eq!(pars_number("oct756"), Ok(0o756));
+        assert_eq!(pars_number("bin101"), Ok(0b101));
+        assert_eq!(pars_number("10"), Ok(10));
+        assert!(pars_number("ten").is_err());
+    }
+
+    #[test]
+    fn test_preprocess_data() {
+        let result = preprocess(lines(&[
+            ".SET DATASEGMENT hex3000",
+            ".SET ROMSIZE 0x10000",
+            ".VAR byte FLAG 1",
+            ".VAR LENTH 10",
+            ".STR NAME \"AB; C\"",
+            ".ARR Byte MYDATA 0,1,2,3,4",
+            ".ARR word HALF 0x1234, 5",
+            "ADD %a0, %a1, %a2"
+        ]), settings()).unwrap();
+
+        assert_eq!(result.settings.data_segment, 0x3000);
+        assert_eq!(result.settings.rom_size, Some(0x10000));
+        assert_eq!(result.lines.len(), 1);
+        assert_eq!(result.lines[0].text, "ADD %a0, %a1, %a2");
+        assert_eq!(result.lines[0].line, 8);
+
+        let data = result.data.iter().map(|d| (d.name.as_str(), d.address, d.bytes.clone())).collect::<Vec<_>>();
+        assert_eq!(data, vec![
+            ("FLAG", 0x3000, vec![1]),
+            ("LENTH", 0x3004, vec![10, 0, 0, 0]),
+            ("NAME", 0x3008, vec![b'A', b'B', b';', b' ', b'C', 0]),
+            ("MYDATA", 0x300E, vec![0, 1, 2, 3, 4]),
+            ("HALF", 0x3014, vec![0x34, 0x12, 5, 0])
+        ]);
+    }
+
+    #[test]
+    fn test_preprocess_rom_size() {
+        let result = preprocess(lines(&[".SET ROMSIZE 0x10000"]), Settings { rom_size: Some(0x8000), ..settings() }).unwrap();
+        assert_eq!(result.settings.rom_size, Some(0x8000));
+        let result = preprocess(lines(&[".SET ROMSIZE 0x10000"]), settings()).unwrap();
+        assert_eq!(result.settings.rom_size, Some(0x10000));
+    }
+
+    #[test]
+    fn test_preprocess_define() {
+        let result = preprocess(lines(&[
+            ".DEF SIZE 4",
+            ".DEF COUNTER %a0",
+            ".VAR TOTAL SIZE",
+            "ADD COUNTER, COUNTER, [SIZE]",
+            "LOAD8 %a1, [SIZES]"
+        ]), settings()).unwrap();
+
+        assert_eq!(result.data[0].bytes, vec![4, 0, 0, 0]);
+        assert_eq!(result.lines[0].text, "ADD %a0, %a0, [4]");
+        assert_eq!(result.lines[1].text, "LOAD8 %a1, [SIZES]");
+
+        // spans point into "ADD COUNTER, COUNTER, [SIZE]"
+        let replacements = &result.lines[0].replacements;
+        assert_eq!(replacements[0], Replacement { start: 4, length: 3, name_length: 7 });
+        assert_eq!(original_span(replacements, 9, 3), (13, 7));
+        assert_eq!(original_span(replacements, 14, 3), (22, 6));
+        assert_eq!(original_span(replacements, 15, 1), (23, 4));
+        assert_eq!(original_span(replacements, 10, 1), (13, 7));
+        assert_eq!(result.lines[0].length(), 28);
+        assert!(result.lines[1].replacements.is_empty());
+    }
+
+    #[test]
+    fn test_preprocess_errors() {
+        let errors = preprocess(lines(&[
+            ".VAR A 1",
+            ".DEF A 2",
+            ".VAR byte B 256",
+            ".STR 1C \"x\"",
+            ".SET SEGMENT 0"
+        ]), settings()).err().unwrap();
+
+        let lines = errors.iter().map(|e| e.line).collect::<Vec<u64>>();
+        assert_eq!(lines, vec![2, 3, 4, 5]);
+        assert_eq!(errors[0].message, "'A' is already defined at test.maasm:1.");
+    }
+
+    #[test]
+    fn test_preprocess_expressions() {
+        let result = preprocess(lines(&[
+            ".DEF BASE 0x100",
+            ".set DATASEGMENT BASE << 4",
+            ".var word SIZE BASE + 2",
+            ".Arr byte PAIR 1 << 1, (BASE >> 8) | 4",
+            ".str QUOTE \"a\\\";b\"",
+            "NOP"
+        ]), settings()).unwrap();
+
+        let data = result.data.iter().map(|d| (d.name.as_str(), d.address, d.bytes.clone())).collect::<Vec<_>>();
+        assert_eq!(data, vec![
+            ("SIZE", 0x1000, vec![0x02, 0x01]),
+            ("PAIR", 0x1002, vec![2, 5]),
+            ("QUOTE", 0x1004, vec![b'a', b'"', b';', b'b', 0])
+        ]);
+
+        let errors = preprocess(lines(&[".VAR A 1 +", ".VAR B -1", ".STR C x", ".DEF"]), settings()).err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.message.as_str()).collect::<Vec<&str>>(), vec![
+            ".VAR A: Expected a number, label or '('.",
+            ".VAR B: Expression result out of u32 range",
+            ".STR C: string must be quoted with '\"'.",
+            ".DEF: missing name."
+        ]);
+    }
+}
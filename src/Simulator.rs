@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::Disassembler::decodes_as;
+use crate::InstructionParser::get_register_name;
+use crate::InstructionSet::{FormKind, InstructionSet, Operation};
+use crate::IsaCheck::immediate_mask;
+use crate::Preprocessor::pars_number;
+
+// register labels from get_register_label
+pub const ZERO: usize = 0b000000;
+pub const PC: usize = 0b101001;
+
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub base: u64,
+    pub writable: bool,
+    pub data: Vec<u8>
+}
+
+impl Region {
+    pub fn new(name: &str, base: u64, size: u64, writable: bool) -> Region {
+        Region { name: name.to_string(), base, writable, data: vec![0; size as usize] }
+    }
+
+    // "rom:BASE:SIZE" or "ram:BASE:SIZE", numbers are written like in the source
+    pub fn pars(text: &str) -> Result<Region, String> {
+        let fields = text.split(':').collect::<Vec<&str>>();
+        if fields.len() != 3 {
+            return Err(format!("Invalid memory region '{}', expected rom:BASE:SIZE or ram:BASE:SIZE.", text));
+        }
+
+        let writable = match fields[0].to_lowercase().as_str() {
+            "rom" => false,
+            "ram" => true,
+            _ => return Err(format!("Invalid memory region '{}', the kind must be rom or ram.", text))
+        };
+        let base = pars_number(fields[1])?;
+        let size = pars_number(fields[2])?;
+        if size == 0 {
+            return Err(format!("Memory region '{}' is empty.", text));
+        }
+
+        Ok(Region::new(&fields[0].to_lowercase(), base, size, writable))
+    }
+
+    fn end(&self) -> u64 {
+        self.base + self.data.len() as u64
+    }
+}
+
+// Addresses are cut to the width of the address bus before they are decoded, so a
+// wider address reaches a mirror of the narrow one.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub address_mask: u64,
+    pub regions: Vec<Region>
+}
+
+impl Memory {
+    pub fn new(address_bits: u32, regions: Vec<Region>) -> Result<Memory, String> {
+        if address_bits == 0 || address_bits > 32 {
+            return Err(format!("The address bus can't be {} bits wide.", address_bits));
+        }
+
+        let address_mask = u64::MAX >> (64 - address_bits);
+        for (index, region) in regions.iter().enumerate() {
+            if region.end() - 1 > address_mask {
+                return Err(format!("The {} at {:#X} doesn't fit on a {} bit address bus.", region.name, region.base, address_bits));
+            }
+            if let Some(other) = regions[..index].iter().find(|r| r.base < region.end() && region.base < r.end()) {
+                return Err(format!("The {} at {:#X} overlaps the {} at {:#X}.", region.name, region.base, other.name, other.base));
+            }
+        }
+
+        Ok(Memory { address_mask, regions })
+    }
+
+    fn locate(&self, address: u64) -> Option<(usize, usize)> {
+        let address = address & self.address_mask;
+        self.regions.iter().position(|r| r.base <= address && address < r.end()).map(|i| (i, (address - self.regions[i].base) as usize))
+    }
+
+    pub fn read_byte(&self, address: u64) -> Result<u8, String> {
+        match self.locate(address) {
+            Some((region, offset)) => Ok(self.regions[region].data[offset]),
+            None => Err(format!("Read from unmapped address {:#X}.", address))
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u64, value: u8) -> Result<(), String> {
+        match self.locate(address) {
+            Some((region, offset)) if self.regions[region].writable => {
+                self.regions[region].data[offset] = value;
+                Ok(())
+            },
+            Some(_) => Err(format!("Write to read only address {:#X}.", address)),
+            None => Err(format!("Write to unmapped address {:#X}.", address))
+        }
+    }
+
+    // little endian, size is 1, 2 or 4 bytes
+    pub fn read(&self, address: u64, size: u32) -> Result<u32, String> {
+        let mut result = 0;
+        for index in (0..size as u64).rev() {
+            result = (result << 8) | self.read_byte(address + index)? as u32;
+        }
+
+        Ok(result)
+    }
+
+    pub fn write(&mut self, address: u64, size: u32, value: u32) -> Result<(), String> {
+        // the whole access is checked first, so a failing write changes nothing
+        for index in 0..size as u64 {
+            match self.locate(address + index) {
+                Some((region, _)) if self.regions[region].writable => (),
+                Some(_) => return Err(format!("Write to read only address {:#X}.", address + index)),
+                None => return Err(format!("Write to unmapped address {:#X}.", address + index))
+            }
+        }
+        for index in 0..size as u64 {
+            self.write_byte(address + index, (value >> (index * 8)) as u8)?;
+        }
+
+        Ok(())
+    }
+
+    // Puts an image into memory, ROM included. Zero bytes outside every region are
+    // skipped, as the flat binary output fills the gaps between .AT blocks with zeros.
+    pub fn load(&mut self, address: u64, bytes: &[u8]) -> Result<(), String> {
+        for (index, &byte) in bytes.iter().enumerate() {
+            match self.locate(address + index as u64) {
+                Some((region, offset)) => self.regions[region].data[offset] = byte,
+                None if byte == 0 => (),
+                None => return Err(format!("The image has data at {:#X}, which isn't mapped.", address + index as u64))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub address: u64,
+    pub size: u32,
+    pub value: u32
+}
+
+// What one instruction did, accesses are in the order they happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub address: u64,
+    pub word: u32,
+    pub cycles: u64,
+    pub accesses: Vec<Access>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // a jump to the instruction itself, the usual way to end a program
+    Halted(u64),
+    Fault(u64, String),
+    CycleLimit
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Halted(address) => write!(f, "halted at {:#010X}", address),
+            Stop::Fault(address, message) => write!(f, "fault at {:#010X}: {}", address, message),
+            Stop::CycleLimit => write!(f, "cycle limit reached")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Decoded {
+    instruction: usize,
+    form: usize,
+    target: usize,
+    source_0: usize,
+    source_1: usize,
+    immediate_0: u32
+}
+
+// Behaviour of the instructions, by the operation in the ISA description and operand form:
+//   load t, i          t = i
+//   load t, i, _       t = memory[i]
+//   load t, _          t = memory[t], the source register isn't encoded by this form
+//   load t, s0, s1     t = memory[s0 + s1]
+//   store t, s0        memory[s0] = t
+//   store t, s0, s1    memory[s0 + s1] = t
+//   move t, s0         t = s0
+//   add / sub          t = s0 + x / t = s0 - x, x is s1 or i
+//   eq                 t = 1 when s0 == x, else 0
+//   jump               to i, t or s0 + s1
+//   jump_not_zero      to i or s0 when t is not zero
+//   jump_zero          to i or s0 when t is zero
+// Loads and stores move the size of the instruction and loads zero extend. Reading PC
+// gives the address of the next instruction and writes to ZERO are dropped. A word that
+// more than one instruction can produce (see check-isa) runs as the first one in the
+// description, with a warning for the address, as the CPU can't tell them apart either.
+//
+// Cycles: one per instruction, one more per load or store and one more per taken jump.
+pub struct Machine<'a> {
+    isa: &'a InstructionSet,
+    pub registers: [u32; 64],
+    pub memory: Memory,
+    pub cycles: u64,
+    pub instructions: u64,
+    // address and message, once per address
+    pub warnings: Vec<(u64, String)>,
+    decoded: HashMap<u32, Option<Decoded>>,
+    // every instruction that can produce a word, for the words with more than one
+    ambiguous: HashMap<u32, Vec<String>>
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(isa: &'a InstructionSet, memory: Memory, reset_vector: u64) -> Machine<'a> {
+        let mut registers = [0; 64];
+        registers[PC] = reset_vector as u32;
+        Machine { isa, registers, memory, cycles: 0, instructions: 0, warnings: vec![], decoded: HashMap::new(), ambiguous: HashMap::new() }
+    }
+
+    pub fn isa(&self) -> &'a InstructionSet {
+        self.isa
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.registers[PC] as u64 & self.memory.address_mask
+    }
+
+    fn decode(&mut self, word: u32) -> Option<Decoded> {
+        if let Some(&decoded) = self.decoded.get(&word) {
+            return decoded;
+        }
+
+        let mut result = None;
+        let mut names = vec![];
+        for (index, inst) in self.isa.instructions().iter().enumerate() {
+            let form_index = match inst.forms.iter().position(|f| decodes_as(word, f, &inst.constraint)) {
+                Some(f) => f,
+                None => continue
+            };
+            names.push(inst.name.clone());
+            if result.is_some() {
+                continue;
+            }
+
+            let constraint = &inst.constraint;
+            let immediate_0 = match inst.forms[form_index].kind {
+                FormKind::Tii => (word & immediate_mask(constraint.immediate_0_number_max, 10)) >> 10,
+                _ => word & immediate_mask(constraint.immediate_0_number_max, 0)
+            };
+            result = Some(Decoded {
+                instruction: index,
+                form: form_index,
+                target: ((word >> 16) & 0x3F) as usize,
+                source_0: ((word >> 10) & 0x3F) as usize,
+                source_1: ((word >> 4) & 0x3F) as usize,
+                immediate_0
+            });
+        }
+
+        if names.len() > 1 {
+            self.ambiguous.insert(word, names);
+        }
+        self.decoded.insert(word, result);
+        result
+    }
+
+    fn reg(&self, label: usize) -> u32 {
+        if label == ZERO {
+            return 0;
+        }
+        self.registers[label]
+    }
+
+    // returns no jump, so it can end a match arm of step
+    fn set(&mut self, label: usize, value: u32) -> Option<u32> {
+        if label != ZERO {
+            self.registers[label] = value;
+        }
+        None
+    }
+
+    fn load(&mut self, address: u32, size: u32, accesses: &mut Vec<Access>) -> Result<u32, String> {
+        let value = self.memory.read(address as u64, size)?;
+        accesses.push(Access { kind: AccessKind::Read, address: address as u64 & self.memory.address_mask, size, value });
+        Ok(value)
+    }
+
+    fn store(&mut self, address: u32, size: u32, value: u32, accesses: &mut Vec<Access>) -> Result<Option<u32>, String> {
+        let value = match size {
+            4 => value,
+            _ => value & ((1 << (size * 8)) - 1)
+        };
+        self.memory.write(address as u64, size, value)?;
+        accesses.push(Access { kind: AccessKind::Write, address: address as u64 & self.memory.address_mask, size, value });
+        Ok(None)
+    }
+
+    // On a fault nothing but the fetch has happened, PC still points to the instruction.
+    pub fn step(&mut self) -> Result<Step, String> {
+        let address = self.pc();
+        let word = self.memory.read(address, 4)?;
+        let mut accesses = vec![Access { kind: AccessKind::Fetch, address, size: 4, value: word }];
+
+        let decoded = match self.decode(word) {
+            Some(d) => d,
+            None => return Err(format!("{:#010X} isn't an instruction.", word))
+        };
+        let inst = &self.isa.instructions()[decoded.instruction];
+        let form = &inst.forms[decoded.form];
+        if let Some(names) = self.ambiguous.get(&word) {
+            if !self.warnings.iter().any(|(a, _)| *a == address) {
+                self.warnings.push((address, format!("{:#010X} could be {}, it ran as {}.", word, names.join(" or "), inst.name)));
+            }
+        }
+        let operation = match inst.operation {
+            Some(o) => o,
+            None => return Err(format!("The ISA description doesn't give an operation for {}.", inst.name))
+        };
+        let size = inst.size;
+
+        let (t, s0, s1, i) = (decoded.target, decoded.source_0, decoded.source_1, decoded.immediate_0);
+        let saved = self.registers;
+        self.registers[PC] = (address as u32).wrapping_add(4);
+
+        // the address of a taken jump
+        let result = match (operation, form.kind) {
+            (Operation::Load, FormKind::Ti) if form.args == 2 => {
+                self.set(t, i);
+                Ok(None)
+            },
+            (Operation::Load, FormKind::Ti) => self.load(i, size, &mut accesses).map(|v| self.set(t, v)),
+            (Operation::Load, FormKind::S) => self.load(self.reg(t), size, &mut accesses).map(|v| self.set(t, v)),
+            (Operation::Load, FormKind::Tss) => self.load(self.reg(s0).wrapping_add(self.reg(s1)), size, &mut accesses).map(|v| self.set(t, v)),
+            (Operation::Store, FormKind::Ts) => self.store(self.reg(s0), size, self.reg(t), &mut accesses),
+            (Operation::Store, FormKind::Tss) => self.store(self.reg(s0).wrapping_add(self.reg(s1)), size, self.reg(t), &mut accesses),
+            (Operation::Move, FormKind::Ts) => Ok(self.set(t, self.reg(s0))),
+            (Operation::Add, FormKind::Tss) => Ok(self.set(t, self.reg(s0).wrapping_add(self.reg(s1)))),
+            (Operation::Add, FormKind::Tsi) => Ok(self.set(t, self.reg(s0).wrapping_add(i))),
+            (Operation::Sub, FormKind::Tss) => Ok(self.set(t, self.reg(s0).wrapping_sub(self.reg(s1)))),
+            (Operation::Sub, FormKind::Tsi) => Ok(self.set(t, self.reg(s0).wrapping_sub(i))),
+            (Operation::Eq, FormKind::Tss) => Ok(self.set(t, (self.reg(s0) == self.reg(s1)) as u32)),
+            (Operation::Eq, FormKind::Tsi) => Ok(self.set(t, (self.reg(s0) == i) as u32)),
+            (Operation::Jump, FormKind::I) => Ok(Some(i)),
+            (Operation::Jump, FormKind::S) => Ok(Some(self.reg(t))),
+            (Operation::Jump, FormKind::Ss) => Ok(Some(self.reg(s0).wrapping_add(self.reg(s1)))),
+            (Operation::JumpNotZero, FormKind::Ti) => Ok(Some(i).filter(|_| self.reg(t) != 0)),
+            (Operation::JumpNotZero, FormKind::Ts) => Ok(Some(self.reg(s0)).filter(|_| self.reg(t) != 0)),
+            (Operation::JumpZero, FormKind::Ti) => Ok(Some(i).filter(|_| self.reg(t) == 0)),
+            (Operation::JumpZero, FormKind::Ts) => Ok(Some(self.reg(s0)).filter(|_| self.reg(t) == 0)),
+            _ => Err(format!("The simulator doesn't know what {} '{}' does.", inst.name, form.kind.name()))
+        };
+
+        let jump = match result {
+            Ok(j) => j,
+            Err(e) => {
+                self.registers = saved;
+                return Err(e);
+            }
+        };
+
+        // the fetch and every load or store
+        let mut cycles = accesses.len() as u64;
+        if let Some(target) = jump {
+            self.registers[PC] = target;
+            cycles += 1;
+        }
+
+        self.cycles += cycles;
+        self.instructions += 1;
+        Ok(Step { address, word, cycles, accesses })
+    }
+
+    pub fn run(&mut self, max_cycles: u64) -> Stop {
+        self.run_traced(max_cycles, |_, _| ())
+    }
+
+    // trace sees every step that completed, with the registers after it
+    pub fn run_traced(&mut self, max_cycles: u64, mut trace: impl FnMut(&Step, &[u32; 64])) -> Stop {
+        while self.cycles < max_cycles {
+            let address = self.pc();
+            match self.step() {
+                Ok(step) => {
+                    trace(&step, &self.registers);
+                    if self.pc() == address {
+                        return Stop::Halted(address);
+                    }
+                },
+                Err(e) => return Stop::Fault(address, e)
+            }
+        }
+
+        Stop::CycleLimit
+    }
+
+    // PC and ZERO, then one line per register group
+    pub fn register_dump(&self) -> String {
+        let mut result = format!("{:<4} {:08X}  {:<4} {:08X}\n", "PC", self.registers[PC], "ZERO", 0);
+        for group in 0..4 {
+            let line = (1 + group * 10..11 + group * 10)
+                .filter_map(|label| get_register_name(label as u8).map(|n| format!("{:<4} {:08X}", n, self.registers[label])))
+                .collect::<Vec<String>>();
+            result += &line.join("  ");
+            result.push('\n');
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InstructionParser::encode_instruction;
+
+    // each line is "MNEMONIC operands", placed one word after the other from address
+    fn assemble(isa: &InstructionSet, memory: &mut Memory, address: u64, program: &[&str]) {
+        for (index, line) in program.iter().enumerate() {
+            let (mnemonic, operands) = line.split_once(' ').unwrap_or((line, ""));
+            let operands = operands.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()).collect();
+            let word = encode_instruction(isa.get(mnemonic).unwrap(), operands, HashMap::new()).unwrap();
+            memory.load(address + index as u64 * 4, &word.to_le_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut memory = Memory::new(16, vec![Region::pars("ram:0:0x100").unwrap(), Region::pars("rom:0xFF00:0x100").unwrap()]).unwrap();
+        memory.write(0xFE, 4, 0x11223344).unwrap_err();
+        memory.write(0x10, 4, 0x11223344).unwrap();
+        assert_eq!(memory.read(0x10, 2), Ok(0x3344));
+        // above the 16 bit address bus the memory repeats
+        assert_eq!(memory.read(0x30012, 2), Ok(0x1122));
+        assert!(memory.write(0xFF10, 1, 1).is_err());
+        assert!(memory.read(0x200, 1).is_err());
+        memory.load(0xFF10, &[1, 2]).unwrap();
+        assert_eq!(memory.read(0xFF10, 2), Ok(0x0201));
+        assert!(memory.load(0x8000, &[0, 0, 1]).is_err());
+
+        assert!(Memory::new(16, vec![Region::pars("ram:0:0x20000").unwrap()]).is_err());
+        assert!(Memory::new(16, vec![Region::pars("ram:0:0x100").unwrap(), Region::pars("rom:0x80:0x100").unwrap()]).is_err());
+        assert!(Region::pars("flash:0:0x100").is_err());
+        assert!(Region::pars("ram:0").is_err());
+    }
+
+    #[test]
+    fn test_run_program() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x800, true), Region::new("rom", 0x800, 0x800, false)]).unwrap();
+        assemble(&isa, &mut memory, 0x800, &[
+            "LOAD8 %a0, [5]",
+            "LOAD8 %a1, [7]",
+            "ADD %a2, %a0, %a1",
+            "LOAD16 %a3, [0x40]",
+            "STORE8 %a2, %a3",
+            "LOAD8 %b0, [0x40], [0]",
+            "EQ %ar0, %b0, %a2",
+            "ZJMP %ar0, [0x824]",
+            "OJMP %ar0, [0x828]",
+            "LOAD8 %c0, [1]",
+            "JMP [0x828]"
+        ]);
+        assemble(&isa, &mut memory, 0xFFC, &["JMP [0x800]"]);
+
+        let mut machine = Machine::new(&isa, memory, 0xFFC);
+        assert_eq!(machine.run(1000), Stop::Halted(0x828));
+        assert_eq!(machine.registers[3], 12);
+        assert_eq!(machine.registers[11], 12);
+        assert_eq!(machine.registers[5], 1);
+        assert_eq!(machine.registers[21], 0);
+        assert_eq!(machine.memory.read(0x40, 1), Ok(12));
+        // 11 instructions, 2 memory accesses and 3 taken jumps
+        assert_eq!(machine.instructions, 11);
+        assert_eq!(machine.cycles, 16);
+    }
+
+    #[test]
+    fn test_run_default_memory_map() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut memory = Memory::new(20, vec![Region::pars("ram:0:0xF0000").unwrap(), Region::pars("rom:0xF0000:0x10000").unwrap()]).unwrap();
+        // a ti jump into the ROM would share bits with the target register, see check-isa
+        assemble(&isa, &mut memory, 0xF0000, &[
+            "LOAD8 %a0, [3]",
+            "ADD %b1, %pc, [8]",
+            "LOAD8 %a1, [0]",
+            "LOAD16 %a2, [0x80]",
+            "ADD %a1, %a1, [1]",
+            "STORE8 %a1, %a2",
+            "EQ %ar0, %a1, %a0",
+            "ZJMP %ar0, %b1",
+            "JMP [0x3F0020]"
+        ]);
+        assemble(&isa, &mut memory, 0xFFFFC, &["JMP [0x3F0000]"]);
+
+        let mut machine = Machine::new(&isa, memory, 0xFFFFC);
+        assert_eq!(machine.run(1000), Stop::Halted(0xF0020));
+        assert_eq!(machine.registers[12], 0xF0010);
+        assert_eq!(machine.registers[2], 3);
+        assert_eq!(machine.memory.read(0x80, 1), Ok(3));
+        // 18 instructions, 3 stores and 4 taken jumps
+        assert_eq!(machine.instructions, 18);
+        assert_eq!(machine.cycles, 25);
+    }
+
+    #[test]
+    fn test_operations_from_isa() {
+        let isa = InstructionSet::from_toml(concat!(
+            "[LDH]\noperation = \"load\"\nsize = 2\nimmediate_0_number_max = 0xFF\n",
+            "forms = [{ args = 2, kind = \"ti\", opcode = 1 }, { args = 3, kind = \"ti\", opcode = 2 }]\n",
+            "[GO]\noperation = \"jump\"\nimmediate_0_number_max = 0x3FFFFF\nforms = [{ args = 1, kind = \"i\", opcode = 3 }]\n",
+            "[NOP]\nforms = [{ args = 1, kind = \"i\", opcode = 4 }]\n"
+        )).unwrap();
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x1000, true)]).unwrap();
+        memory.write(0x40, 4, 0x11223344).unwrap();
+        assemble(&isa, &mut memory, 0, &["LDH %a0, [0x40]", "LDH %a1, [0x40], [0]", "GO [0x10]"]);
+        assemble(&isa, &mut memory, 0x10, &["NOP [0]"]);
+
+        let mut machine = Machine::new(&isa, memory, 0);
+        assert_eq!(machine.run(1000), Stop::Fault(0x10, String::from("The ISA description doesn't give an operation for NOP.")));
+        assert_eq!(machine.registers[1], 0x40);
+        assert_eq!(machine.registers[2], 0x3344);
+    }
+
+    #[test]
+    fn test_sub() {
+        // ADD and SUB share their opcodes in the default description, see check-isa
+        let isa = InstructionSet::load(None).unwrap();
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x1000, true)]).unwrap();
+        assemble(&isa, &mut memory, 0, &["LOAD8 %a0, [7]", "SUB %a1, %a0, [2]", "JMP [4]"]);
+        let mut machine = Machine::new(&isa, memory, 0);
+        assert_eq!(machine.run(20), Stop::CycleLimit);
+        assert_eq!(machine.registers[2], 9);
+        assert_eq!(machine.warnings, vec![(4, String::from("0x80020402 could be ADD or SUB, it ran as ADD."))]);
+
+        let isa = InstructionSet::from_toml(concat!(
+            "[LI]\noperation = \"load\"\nimmediate_0_number_max = 0xFF\nforms = [{ args = 2, kind = \"ti\", opcode = 1 }]\n",
+            "[SUB]\noperation = \"sub\"\nimmediate_0_number_max = 0xFF\nforms = [{ args = 3, kind = \"tsi\", opcode = 2 }]\n",
+            "[GO]\noperation = \"jump\"\nimmediate_0_number_max = 0x3FFFFF\nforms = [{ args = 1, kind = \"i\", opcode = 3 }]\n"
+        )).unwrap();
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x1000, true)]).unwrap();
+        assemble(&isa, &mut memory, 0, &["LI %a0, [7]", "SUB %a1, %a0, [2]", "GO [8]"]);
+        let mut machine = Machine::new(&isa, memory, 0);
+        assert_eq!(machine.run(20), Stop::Halted(8));
+        assert_eq!(machine.registers[2], 5);
+        assert_eq!(machine.warnings, vec![]);
+    }
+
+    #[test]
+    fn test_faults() {
+        let isa = InstructionSet::load(None).unwrap();
+        let mut memory = Memory::new(12, vec![Region::new("ram", 0, 0x80, true), Region::new("rom", 0x80, 0xF80, false)]).unwrap();
+        assemble(&isa, &mut memory, 0, &["LOAD16 %a0, [0x90]", "LOAD8 %a1, [9]", "STORE8 %a1, %a0"]);
+
+        let mut machine = Machine::new(&isa, memory.clone(), 0);
+        assert_eq!(machine.run(1000), Stop::Fault(8, String::from("Write to read only address 0x90.")));
+        assert_eq!(machine.pc(), 8);
+        assert_eq!(machine.registers[2], 9);
+
+        // the zero word after the program
+        let mut machine = Machine::new(&isa, memory.clone(), 4);
+        machine.memory.write(8, 4, 0).unwrap();
+        assert_eq!(machine.run(1000), Stop::Fault(8, String::from("0x00000000 isn't an instruction.")));
+
+        let mut machine = Machine::new(&isa, memory, 0x10);
+        machine.memory.load(0x10, &0xC0000010_u32.to_le_bytes()).unwrap();
+        assert_eq!(machine.run(1000), Stop::Halted(0x10));
+        let mut machine = Machine::new(&isa, machine.memory.clone(), 0x14);
+        machine.memory.load(0x14, &0xC0000018_u32.to_le_bytes()).unwrap();
+        machine.memory.load(0x18, &0xC0000014_u32.to_le_bytes()).unwrap();
+        assert_eq!(machine.run(5), Stop::CycleLimit);
+        assert_eq!(machine.cycles, 6);
+    }
+}
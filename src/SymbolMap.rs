@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use crate::Preprocessor::Data;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Label,
+    Data
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Data => "data"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    pub kind: SymbolKind,
+    // number of bytes of a .VAR, .ARR or .STR, labels have no size
+    pub size: Option<u64>
+}
+
+// Data names are in the label map too, they are reported once as data symbols.
+// The result is sorted by address, symbols at the same address by name.
+pub fn collect_symbols(labels: &HashMap<String, u64>, data: &[Data]) -> Vec<Symbol> {
+    let mut result = vec![];
+    // anonymous labels have no name to look up
+    for (name, &address) in labels.iter().filter(|(n, _)| !n.starts_with('@')) {
+        match data.iter().find(|d| &d.name == name) {
+            Some(d) => result.push(Symbol { name: name.clone(), address: d.address, kind: SymbolKind::Data, size: Some(d.bytes.len() as u64) }),
+            None => result.push(Symbol { name: name.clone(), address, kind: SymbolKind::Label, size: None })
+        }
+    }
+
+    result.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+    result
+}
+
+pub fn to_text(symbols: &[Symbol]) -> String {
+    let mut result = String::from("; symbols by address\n");
+    for symbol in symbols.iter() {
+        result += &text_line(symbol);
+    }
+
+    let mut by_name = symbols.iter().collect::<Vec<&Symbol>>();
+    by_name.sort_by(|a, b| (&a.name, a.address).cmp(&(&b.name, b.address)));
+    result += "\n; symbols by name\n";
+    for symbol in by_name {
+        result += &text_line(symbol);
+    }
+
+    result
+}
+
+fn text_line(symbol: &Symbol) -> String {
+    let size = match symbol.size {
+        Some(s) => s.to_string(),
+        None => String::new()
+    };
+    format!("{:08X}  {:<5}  {:>6}  {}\n", symbol.address, symbol.kind.name(), size, symbol.name)
+}
+
+pub fn to_json(symbols: &[Symbol]) -> String {
+    let symbols = symbols.iter().map(|s| serde_json::json!({
+        "name": s.name,
+        "address": s.address,
+        "kind": s.kind.name(),
+        "size": s.size
+    })).collect::<Vec<serde_json::Value>>();
+
+    let mut result = serde_json::to_string_pretty(&serde_json::json!({ "symbols": symbols })).unwrap_or_default();
+    result.push('\n');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Vec<Symbol> {
+        let labels = HashMap::from([
+            (String::from("start"), 0x100),
+            (String::from("buffer"), 0x2000),
+            (String::from("alias"), 0x100)
+        ]);
+        let data = vec![Data { name: String::from("buffer"), address: 0x2000, bytes: vec![0; 6], file: String::from("test.maasm"), line: 2, column: 1, length: 15, included_from: vec![], code: false }];
+        collect_symbols(&labels, &data)
+    }
+
+    #[test]
+    fn test_collect_symbols() {
+        assert_eq!(symbols(), vec![
+            Symbol { name: String::from("alias"), address: 0x100, kind: SymbolKind::Label, size: None },
+            Symbol { name: String::from("start"), address: 0x100, kind: SymbolKind::Label, size: None },
+            Symbol { name: String::from("buffer"), address: 0x2000, kind: SymbolKind::Data, size: Some(6) }
+        ]);
+    }
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(to_text(&symbols()), concat!(
+            "; symbols by address\n",
+            "00000100  label          alias\n",
+            "00000100  label          start\n",
+            "00002000  data        6  buffer\n",
+            "\n",
+            "; symbols by name\n",
+            "00000100  label          alias\n",
+            "00002000  data        6  buffer\n",
+            "00000100  label          start\n"
+        ));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json: serde_json::Value = serde_json::from_str(&to_json(&symbols())).unwrap();
+        assert_eq!(json["symbols"][0], serde_json::json!({ "name": "alias", "address": 256, "kind": "label", "size": null }));
+        assert_eq!(json["symbols"][2], serde_json::json!({ "name": "buffer", "address": 8192, "kind": "data", "size": 6 }));
+    }
+}
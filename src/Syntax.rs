@@ -0,0 +1,656 @@
+use std::collections::HashMap;
+
+// character offset from the start of the line text and length in characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: u64,
+    pub length: u64
+}
+
+impl Span {
+    fn new(start: usize, end: usize) -> Span {
+        Span { start: start as u64, length: (end - start) as u64 }
+    }
+
+    fn to(self, other: Span) -> Span {
+        Span { start: self.start, length: other.start + other.length - self.start }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span
+}
+
+impl SyntaxError {
+    fn new(message: String, span: Span) -> SyntaxError {
+        SyntaxError { message, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    // mnemonics, labels and register names after a %
+    Name(String),
+    Number(u64),
+    // the text between the quotes, escapes are kept as written
+    String(String),
+    // , : [ ] ( ) and the operators, << >> == != <= >= are one token
+    Symbol(&'static str)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span
+}
+
+const SYMBOLS: [&str; 22] = [
+    "<<", ">>", "==", "!=", "<=", ">=",
+    ",", ":", "[", "]", "(", ")", "+", "-", "*", "/", "%", "&", "|", "^", "<", ">"
+];
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// The end of the name at index, if there is one. Besides plain names this is .loop for a
+// local label, check_ram.loop for the same label from anywhere, and @@, @f and @b.
+fn name_end(chars: &[char], index: usize) -> Option<usize> {
+    let at = |i: usize| chars.get(i).copied().unwrap_or(' ');
+    if at(index) == '@' {
+        return match at(index + 1) {
+            '@' | 'f' | 'F' | 'b' | 'B' if !is_name_char(at(index + 2)) => Some(index + 2),
+            _ => None
+        };
+    }
+
+    let mut end = index;
+    if at(end) == '.' {
+        end += 1;
+    }
+    if !is_name_start(at(end)) {
+        return None;
+    }
+    loop {
+        while is_name_char(at(end)) {
+            end += 1;
+        }
+        if at(end) != '.' || !is_name_start(at(end + 1)) {
+            return Some(end);
+        }
+        end += 1;
+    }
+}
+
+// 0x, 0o and 0b in either case, '_' separates digits
+fn pars_number(text: &str) -> Result<u64, String> {
+    let lower = text.to_lowercase().replace('_', "");
+    let (digits, radix) = match lower.get(..2) {
+        Some("0x") => (&lower[2..], 16),
+        Some("0o") => (&lower[2..], 8),
+        Some("0b") => (&lower[2..], 2),
+        _ => (lower.as_str(), 10)
+    };
+    u64::from_str_radix(digits, radix).map_err(|e| format!("Invalid number '{}': {}", text, e))
+}
+
+pub fn tokenize(text: &str) -> Result<Vec<Token>, SyntaxError> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        let start = index;
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        let kind = if let Some(end) = name_end(&chars, index) {
+            index = end;
+            TokenKind::Name(chars[start..index].iter().collect())
+        } else if c.is_ascii_digit() {
+            while index < chars.len() && is_name_char(chars[index]) {
+                index += 1;
+            }
+            let number = chars[start..index].iter().collect::<String>();
+            match pars_number(&number) {
+                Ok(n) => TokenKind::Number(n),
+                Err(e) => return Err(SyntaxError::new(e, Span::new(start, index)))
+            }
+        } else if c == '"' {
+            index += 1;
+            while index < chars.len() && chars[index] != '"' {
+                if chars[index] == '\\' {
+                    index += 1;
+                }
+                index += 1;
+            }
+            if index >= chars.len() {
+                return Err(SyntaxError::new(String::from("Unterminated string."), Span::new(start, chars.len())));
+            }
+            index += 1;
+            TokenKind::String(chars[start + 1..index - 1].iter().collect())
+        } else {
+            let rest = chars[index..chars.len().min(index + 2)].iter().collect::<String>();
+            match SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+                Some(s) => {
+                    index += s.len();
+                    TokenKind::Symbol(s)
+                },
+                None => return Err(SyntaxError::new(format!("Unexpected character '{}'.", c), Span::new(start, start + 1)))
+            }
+        };
+        tokens.push(Token { kind, span: Span::new(start, index) });
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor
+}
+
+impl BinaryOp {
+    fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        match symbol {
+            "+" => Some(BinaryOp::Add),
+            "-" => Some(BinaryOp::Sub),
+            "*" => Some(BinaryOp::Mul),
+            "/" => Some(BinaryOp::Div),
+            "%" => Some(BinaryOp::Rem),
+            "<<" => Some(BinaryOp::Shl),
+            ">>" => Some(BinaryOp::Shr),
+            "&" => Some(BinaryOp::And),
+            "|" => Some(BinaryOp::Or),
+            "^" => Some(BinaryOp::Xor),
+            _ => None
+        }
+    }
+
+    // shifts and bit operators bind the weakest, then + and -, then * / %
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Shl | BinaryOp::Shr | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor => 1,
+            BinaryOp::Add | BinaryOp::Sub => 2,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 3
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(u64),
+    Symbol(String, Span),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+impl Expr {
+    // Replaces the label names rename gives a new name for, like .loop with check_ram.loop
+    pub fn rename(&mut self, rename: &impl Fn(&str) -> Option<String>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Symbol(name, _) => {
+                if let Some(n) = rename(name) {
+                    *name = n;
+                }
+            },
+            Expr::Negate(e) => e.rename(rename),
+            Expr::Binary(_, left, right) => {
+                left.rename(rename);
+                right.rename(rename);
+            }
+        }
+    }
+
+    // Calculated in i64, only the result has to fit in 32 bits
+    pub fn evaluate(&self, labels: &HashMap<String, u64>) -> Result<u32, String> {
+        let result = self.evaluate_i64(labels)?;
+        if result < 0 || result > u32::MAX as i64 {
+            return Err(String::from("Expression result out of u32 range"));
+        }
+        Ok(result as u32)
+    }
+
+    fn evaluate_i64(&self, labels: &HashMap<String, u64>) -> Result<i64, String> {
+        let overflow = || String::from("Expression overflow");
+        match self {
+            Expr::Number(n) => i64::try_from(*n).map_err(|_| overflow()),
+            Expr::Symbol(name, _) => match labels.get(name) {
+                Some(&l) => i64::try_from(l).map_err(|_| overflow()),
+                None => Err(format!("Unknown symbol: {}", name))
+            },
+            Expr::Negate(e) => e.evaluate_i64(labels)?.checked_neg().ok_or_else(overflow),
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate_i64(labels)?;
+                let right = right.evaluate_i64(labels)?;
+                let result = match op {
+                    BinaryOp::Add => left.checked_add(right),
+                    BinaryOp::Sub => left.checked_sub(right),
+                    BinaryOp::Mul => left.checked_mul(right),
+                    BinaryOp::Div if right == 0 => return Err(String::from("Division by zero")),
+                    BinaryOp::Div => left.checked_div(right),
+                    BinaryOp::Rem if right == 0 => return Err(String::from("Modulo by zero")),
+                    BinaryOp::Rem => left.checked_rem(right),
+                    // checked_shl only checks the shift count, bits shifted out are an overflow too
+                    BinaryOp::Shl => u32::try_from(right).ok().and_then(|r| left.checked_shl(r)).filter(|v| v >> right == left),
+                    BinaryOp::Shr => u32::try_from(right).ok().and_then(|r| left.checked_shr(r)),
+                    BinaryOp::And => Some(left & right),
+                    BinaryOp::Or => Some(left | right),
+                    BinaryOp::Xor => Some(left ^ right)
+                };
+                result.ok_or_else(overflow)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperandKind {
+    // the name without the %
+    Register(String),
+    // [expression]
+    Address(Expr),
+    Value(Expr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+    pub kind: OperandKind,
+    pub text: String,
+    pub span: Span
+}
+
+impl Operand {
+    pub fn rename(&mut self, rename: &impl Fn(&str) -> Option<String>) {
+        match &mut self.kind {
+            OperandKind::Register(_) => (),
+            OperandKind::Address(expr) | OperandKind::Value(expr) => expr.rename(rename)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Label {
+        name: String,
+        span: Span
+    },
+    // the name is upper case, the arguments are left as text, every directive has its own syntax
+    Directive {
+        name: String,
+        span: Span,
+        arguments: String
+    },
+    Instruction {
+        mnemonic: String,
+        span: Span,
+        operands: Vec<Operand>
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Result<Parser<'a>, SyntaxError> {
+        Ok(Parser { text, tokens: tokenize(text)?, pos: 0 })
+    }
+
+    fn current(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn symbol(&self) -> Option<&'static str> {
+        match self.current() {
+            Some(Token { kind: TokenKind::Symbol(s), .. }) => Some(s),
+            _ => None
+        }
+    }
+
+    // the end of the text, for errors about something missing
+    fn end(&self) -> Span {
+        Span::new(self.text.chars().count(), self.text.chars().count())
+    }
+
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        match self.current() {
+            Some(token) => {
+                let found = slice(self.text, token.span);
+                SyntaxError::new(format!("Expected {}, found '{}'.", expected, found), token.span)
+            },
+            None => SyntaxError::new(format!("Expected {}.", expected), self.end())
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<Span, SyntaxError> {
+        match self.current() {
+            Some(Token { kind: TokenKind::Symbol(s), span }) if *s == symbol => {
+                let span = *span;
+                self.pos += 1;
+                Ok(span)
+            },
+            _ => Err(self.unexpected(&format!("'{}'", symbol)))
+        }
+    }
+
+    fn expression(&mut self, min_precedence: u8) -> Result<(Expr, Span), SyntaxError> {
+        let (mut left, mut span) = self.primary()?;
+        while let Some(op) = self.symbol().and_then(BinaryOp::from_symbol) {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            // left associative
+            let (right, right_span) = self.expression(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+            span = span.to(right_span);
+        }
+
+        Ok((left, span))
+    }
+
+    fn primary(&mut self) -> Result<(Expr, Span), SyntaxError> {
+        let token = match self.current() {
+            Some(t) => t.clone(),
+            None => return Err(self.unexpected("a number, label or '('"))
+        };
+        match token.kind {
+            TokenKind::Number(n) => {
+                self.pos += 1;
+                Ok((Expr::Number(n), token.span))
+            },
+            TokenKind::Name(name) => {
+                self.pos += 1;
+                Ok((Expr::Symbol(name, token.span), token.span))
+            },
+            TokenKind::Symbol("(") => {
+                self.pos += 1;
+                let (expr, _) = self.expression(0)?;
+                let close = self.expect_symbol(")")?;
+                Ok((expr, token.span.to(close)))
+            },
+            TokenKind::Symbol("-") => {
+                self.pos += 1;
+                let (expr, span) = self.primary()?;
+                Ok((Expr::Negate(Box::new(expr)), token.span.to(span)))
+            },
+            _ => Err(self.unexpected("a number, label or '('"))
+        }
+    }
+
+    // %REG, [expression] or expression
+    fn operand(&mut self) -> Result<Operand, SyntaxError> {
+        let start = match self.current() {
+            Some(t) => t.span,
+            None => return Err(self.unexpected("an operand"))
+        };
+
+        let (kind, span) = match self.symbol() {
+            Some("%") => {
+                self.pos += 1;
+                match self.current() {
+                    Some(Token { kind: TokenKind::Name(name), span }) if span.start == start.start + 1 => {
+                        let (name, span) = (name.clone(), *span);
+                        self.pos += 1;
+                        (OperandKind::Register(name), start.to(span))
+                    },
+                    _ => return Err(self.unexpected("a register name after '%'"))
+                }
+            },
+            Some("[") => {
+                self.pos += 1;
+                let (expr, _) = self.expression(0)?;
+                let close = self.expect_symbol("]")?;
+                (OperandKind::Address(expr), start.to(close))
+            },
+            _ => {
+                let (expr, span) = self.expression(0)?;
+                (OperandKind::Value(expr), span)
+            }
+        };
+
+        Ok(Operand { kind, text: slice(self.text, span).to_string(), span })
+    }
+
+    fn finish(&self) -> Result<(), SyntaxError> {
+        match self.current() {
+            Some(_) => Err(self.unexpected("the end of the line")),
+            None => Ok(())
+        }
+    }
+}
+
+fn slice(text: &str, span: Span) -> &str {
+    let mut indices = text.char_indices().map(|(i, _)| i).chain([text.len()]);
+    let start = indices.nth(span.start as usize).unwrap_or(text.len());
+    let end = match span.length {
+        0 => start,
+        l => indices.nth(l as usize - 1).unwrap_or(text.len())
+    };
+    &text[start..end]
+}
+
+// The labels in front of a line, "a: .b: ADD %a0, %a0, [1]" starts with a and .b, and the
+// character offset of what follows them. Only the start of the line is looked at, so the
+// rest doesn't have to be valid yet.
+pub fn split_labels(text: &str) -> (Vec<(String, Span)>, usize) {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut labels = vec![];
+    let mut rest = 0;
+    loop {
+        let mut index = rest;
+        while index < chars.len() && chars[index].is_whitespace() {
+            index += 1;
+        }
+        let start = index;
+        index = match name_end(&chars, index) {
+            Some(end) => end,
+            None => return (labels, rest)
+        };
+        let end = index;
+        while index < chars.len() && chars[index].is_whitespace() {
+            index += 1;
+        }
+        if chars.get(index) != Some(&':') {
+            return (labels, rest);
+        }
+
+        labels.push((chars[start..end].iter().collect(), Span::new(start, end)));
+        rest = index + 1;
+        while rest < chars.len() && chars[rest].is_whitespace() {
+            rest += 1;
+        }
+    }
+}
+
+// One line without its comment, after preprocessing
+pub fn parse_statement(text: &str) -> Result<Statement, SyntaxError> {
+    let (labels, rest) = split_labels(text);
+    if let Some((name, span)) = labels.first() {
+        let length = text.chars().count();
+        if labels.len() == 1 && rest == length {
+            return Ok(Statement::Label { name: name.clone(), span: *span });
+        }
+        let span = match labels.get(1) {
+            Some((_, s)) => *s,
+            None => Span::new(rest, rest + text.chars().skip(rest).take_while(|c| !c.is_whitespace()).count())
+        };
+        return Err(SyntaxError::new(String::from("Expected the end of the line after a label."), span));
+    }
+
+    if let Some(rest) = text.strip_prefix('.') {
+        let name = rest.split(char::is_whitespace).next().unwrap_or_default();
+        return Ok(Statement::Directive {
+            name: name.to_uppercase(),
+            span: Span::new(0, name.chars().count() + 1),
+            arguments: rest[name.len()..].trim().to_string()
+        });
+    }
+
+    let mut parser = Parser::new(text)?;
+    let (mnemonic, span) = match parser.current() {
+        Some(Token { kind: TokenKind::Name(name), span }) => (name.clone(), *span),
+        _ => return Err(parser.unexpected("an instruction or label"))
+    };
+    parser.pos += 1;
+
+    let mut operands = vec![];
+    if parser.current().is_some() {
+        loop {
+            operands.push(parser.operand()?);
+            match parser.symbol() {
+                Some(",") => parser.pos += 1,
+                _ => break
+            }
+        }
+        parser.finish()?;
+    }
+
+    Ok(Statement::Instruction { mnemonic, span, operands })
+}
+
+pub fn parse_operand(text: &str) -> Result<Operand, SyntaxError> {
+    let mut parser = Parser::new(text)?;
+    let operand = parser.operand()?;
+    parser.finish()?;
+    Ok(operand)
+}
+
+pub fn parse_expression(text: &str) -> Result<Expr, SyntaxError> {
+    let mut parser = Parser::new(text)?;
+    let (expr, _) = parser.expression(0)?;
+    parser.finish()?;
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<TokenKind> {
+        tokenize(text).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(kinds("LOAD8 %a0, [0xFF]"), vec![
+            TokenKind::Name(String::from("LOAD8")),
+            TokenKind::Symbol("%"),
+            TokenKind::Name(String::from("a0")),
+            TokenKind::Symbol(","),
+            TokenKind::Symbol("["),
+            TokenKind::Number(0xFF),
+            TokenKind::Symbol("]")
+        ]);
+        assert_eq!(kinds("a<<0B1_0>>b<c"), vec![
+            TokenKind::Name(String::from("a")),
+            TokenKind::Symbol("<<"),
+            TokenKind::Number(2),
+            TokenKind::Symbol(">>"),
+            TokenKind::Name(String::from("b")),
+            TokenKind::Symbol("<"),
+            TokenKind::Name(String::from("c"))
+        ]);
+        assert_eq!(kinds("check_ram.loop+.next-@f @B"), vec![
+            TokenKind::Name(String::from("check_ram.loop")),
+            TokenKind::Symbol("+"),
+            TokenKind::Name(String::from(".next")),
+            TokenKind::Symbol("-"),
+            TokenKind::Name(String::from("@f")),
+            TokenKind::Name(String::from("@B"))
+        ]);
+        assert!(tokenize("@x").is_err());
+        assert_eq!(kinds("\"a\\\"b\""), vec![TokenKind::String(String::from("a\\\"b"))]);
+        assert_eq!(tokenize("1 + 0xZZ").unwrap_err().span, Span { start: 4, length: 4 });
+        assert_eq!(tokenize("a $ b").unwrap_err().message, "Unexpected character '$'.");
+    }
+
+    #[test]
+    fn test_parse_statement() {
+        assert_eq!(parse_statement("loop:"), Ok(Statement::Label { name: String::from("loop"), span: Span { start: 0, length: 4 } }));
+        assert_eq!(parse_statement(".AT  0x100"), Ok(Statement::Directive {
+            name: String::from("AT"),
+            span: Span { start: 0, length: 3 },
+            arguments: String::from("0x100")
+        }));
+        assert_eq!(parse_statement(".incbin \"a.bin\""), Ok(Statement::Directive {
+            name: String::from("INCBIN"),
+            span: Span { start: 0, length: 7 },
+            arguments: String::from("\"a.bin\"")
+        }));
+
+        let operands = match parse_statement("ADDI %A0, [1], label & 0xFF") {
+            Ok(Statement::Instruction { mnemonic, operands, .. }) if mnemonic == "ADDI" => operands,
+            s => panic!("unexpected statement {:?}", s)
+        };
+        assert_eq!(operands.iter().map(|o| (o.text.as_str(), o.span.start, o.span.length)).collect::<Vec<(&str, u64, u64)>>(),
+            vec![("%A0", 5, 3), ("[1]", 10, 3), ("label & 0xFF", 15, 12)]);
+        assert_eq!(operands[0].kind, OperandKind::Register(String::from("A0")));
+        assert_eq!(operands[1].kind, OperandKind::Address(Expr::Number(1)));
+
+        assert_eq!(parse_statement("NOP"), Ok(Statement::Instruction { mnemonic: String::from("NOP"), span: Span { start: 0, length: 3 }, operands: vec![] }));
+        assert_eq!(parse_statement("JMP [1"), Err(SyntaxError::new(String::from("Expected ']'."), Span { start: 6, length: 0 })));
+        assert_eq!(parse_statement("JMP % a0").unwrap_err().message, "Expected a register name after '%', found 'a0'.");
+        assert_eq!(parse_statement("loop: JMP loop").unwrap_err().span, Span { start: 6, length: 3 });
+        assert!(parse_statement("ADD %a0 %a1").is_err());
+        assert!(parse_statement("[0]").is_err());
+    }
+
+    #[test]
+    fn test_split_labels() {
+        let labels = |text: &str| {
+            let (labels, rest) = split_labels(text);
+            (labels.into_iter().map(|(n, s)| (n, s.start)).collect::<Vec<(String, u64)>>(), rest)
+        };
+        assert_eq!(labels("loop: ADD %a0, %a0, [1]"), (vec![(String::from("loop"), 0)], 6));
+        assert_eq!(labels("a:b :  .AT 0x10"), (vec![(String::from("a"), 0), (String::from("b"), 2)], 7));
+        assert_eq!(labels("end:"), (vec![(String::from("end"), 0)], 4));
+        assert_eq!(labels(".loop: @@: JMP @b"), (vec![(String::from(".loop"), 0), (String::from("@@"), 7)], 11));
+        assert_eq!(labels("LOAD8 %a0, [0]"), (vec![], 0));
+        assert_eq!(labels(".STR s \"a: b\""), (vec![], 0));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let labels = HashMap::from([(String::from("top"), 0x1234)]);
+        let evaluate = |text: &str| parse_expression(text).unwrap().evaluate(&labels);
+        assert_eq!(evaluate("1 + 2 << 3"), Ok(24));
+        assert_eq!(evaluate("(top & 0xFF00) >> 8"), Ok(0x12));
+        assert_eq!(evaluate("-1 + top"), Ok(0x1233));
+        assert_eq!(evaluate("10 % 3"), Ok(1));
+        assert_eq!(evaluate("1 << 64"), Err(String::from("Expression overflow")));
+        assert_eq!(evaluate("-1"), Err(String::from("Expression result out of u32 range")));
+        assert_eq!(evaluate("bottom"), Err(String::from("Unknown symbol: bottom")));
+    }
+
+    #[test]
+    fn test_evaluate_shift() {
+        let evaluate = |text: &str| parse_expression(text).unwrap().evaluate(&HashMap::new());
+        assert_eq!(evaluate("1 << 62 >> 60"), Ok(4));
+        assert_eq!(evaluate("1 << 31"), Ok(0x80000000));
+        assert_eq!(evaluate("3 << 62"), Err(String::from("Expression overflow")));
+        assert_eq!(evaluate("1 << 63"), Err(String::from("Expression overflow")));
+        assert_eq!(evaluate("-1 << 4"), Err(String::from("Expression result out of u32 range")));
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::Diagnostic::Diagnostic;
+use crate::FileParser::Expect;
+use crate::InstructionParser::{calculate_expression, get_register_label};
+use crate::Simulator::{Machine, Stop, ZERO};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Number(u64),
+    Register(usize),
+    // address and size in bytes
+    Memory(u64, u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    LessEqual,
+    GreaterEqual,
+    Less,
+    Greater
+}
+
+// longer operators first, so <= isn't taken for <
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessEqual),
+    (">=", Comparison::GreaterEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater)
+];
+
+impl Comparison {
+    fn holds(&self, left: u64, right: u64) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::LessEqual => left <= right,
+            Comparison::GreaterEqual => left >= right,
+            Comparison::Less => left < right,
+            Comparison::Greater => left > right
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Check {
+    left: (String, Operand),
+    comparison: Comparison,
+    right: (String, Operand),
+    expect: Expect
+}
+
+// The .EXPECT lines of one test, checked together the first time the program gets to address
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub name: String,
+    pub address: u64,
+    checks: Vec<Check>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass,
+    // one message per .EXPECT that didn't hold, with the file and line of it
+    Fail(Vec<Diagnostic>),
+    NotReached
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(_) => write!(f, "FAIL"),
+            Outcome::NotReached => write!(f, "FAIL (not reached)")
+        }
+    }
+}
+
+fn pars_value(text: &str, labels: &HashMap<String, u64>) -> Result<u64, String> {
+    match calculate_expression(text, labels.clone()) {
+        Ok(v) => Ok(v as u64),
+        Err(e) => Err(format!("'{}' isn't a valid expression: {}", text, e))
+    }
+}
+
+// %REG, [ADDR] with an optional BYTE, WORD or DWORD in front, or an expression of numbers and labels
+fn pars_operand(text: &str, labels: &HashMap<String, u64>) -> Result<Operand, String> {
+    if let Some(name) = text.strip_prefix('%') {
+        return match get_register_label(name) {
+            Ok(l) => Ok(Operand::Register(l as usize)),
+            Err(_) => Err(format!("Unknown register '{}'.", text))
+        };
+    }
+
+    // only a size keyword is taken off, any other first word is part of an expression
+    let (size, rest) = match text.split_once(char::is_whitespace) {
+        Some((kind, rest)) if kind.eq_ignore_ascii_case("BYTE") => (1, rest.trim()),
+        Some((kind, rest)) if kind.eq_ignore_ascii_case("WORD") => (2, rest.trim()),
+        Some((kind, rest)) if kind.eq_ignore_ascii_case("DWORD") => (4, rest.trim()),
+        _ => (4, text)
+    };
+    match rest.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        Some(address) => pars_value(address.trim(), labels).map(|a| Operand::Memory(a, size)),
+        None if rest.len() != text.len() => Err(format!("Expected a memory address in brackets, found '{}'.", rest)),
+        None => pars_value(text, labels).map(Operand::Number)
+    }
+}
+
+fn pars_check(expect: &Expect, labels: &HashMap<String, u64>) -> Result<Check, String> {
+    let (index, symbol, comparison) = match COMPARISONS.iter().filter_map(|(s, c)| expect.text.find(s).map(|i| (i, *s, *c))).min_by_key(|m| m.0) {
+        Some(m) => m,
+        None => return Err(format!("'{}' has no comparison, expected one of == != < <= > >=.", expect.text))
+    };
+
+    let left = expect.text[..index].trim();
+    let right = expect.text[index + symbol.len()..].trim();
+    Ok(Check {
+        left: (left.to_string(), pars_operand(left, labels)?),
+        comparison,
+        right: (right.to_string(), pars_operand(right, labels)?),
+        expect: expect.clone()
+    })
+}
+
+// .EXPECT lines at the same address under the same label are one test
+pub fn pars_checkpoints(expects: &[Expect], labels: &HashMap<String, u64>) -> Result<Vec<Checkpoint>, Vec<Diagnostic>> {
+    let mut result: Vec<Checkpoint> = vec![];
+    let mut diagnostics = vec![];
+    for expect in expects {
+        let check = match pars_check(expect, labels) {
+            Ok(c) => c,
+            Err(e) => {
+                diagnostics.push(Diagnostic::error(&expect.file, expect.line, expect.column, format!(".EXPECT: {}", e)));
+                continue;
+            }
+        };
+
+        let name = match &expect.label {
+            Some(l) => l.clone(),
+            None => format!("{}:{}", expect.file, expect.line)
+        };
+        match result.iter_mut().find(|c| c.name == name && c.address == expect.address) {
+            Some(checkpoint) => checkpoint.checks.push(check),
+            None => result.push(Checkpoint { name, address: expect.address, checks: vec![check] })
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(result)
+}
+
+fn value(machine: &Machine, operand: &Operand) -> Result<u64, String> {
+    match operand {
+        Operand::Number(n) => Ok(*n),
+        Operand::Register(l) if *l == ZERO => Ok(0),
+        Operand::Register(l) => Ok(machine.registers[*l] as u64),
+        Operand::Memory(address, size) => machine.memory.read(*address, *size).map(|v| v as u64)
+    }
+}
+
+fn evaluate(machine: &Machine, checkpoint: &Checkpoint) -> Outcome {
+    let mut failures = vec![];
+    for check in checkpoint.checks.iter() {
+        let expect = &check.expect;
+        let values = value(machine, &check.left.1).and_then(|l| value(machine, &check.right.1).map(|r| (l, r)));
+        let message = match values {
+            Ok((left, right)) if check.comparison.holds(left, right) => continue,
+            Ok((left, right)) => match check.right.1 {
+                Operand::Number(_) => format!("expected {}, but {} is {:#X}", expect.text, check.left.0, left),
+                _ => format!("expected {}, but {} is {:#X} and {} is {:#X}", expect.text, check.left.0, left, check.right.0, right)
+            },
+            Err(e) => format!("expected {}, but {}", expect.text, e)
+        };
+        failures.push(Diagnostic::error(&expect.file, expect.line, expect.column, message));
+    }
+
+    if failures.is_empty() { Outcome::Pass } else { Outcome::Fail(failures) }
+}
+
+// Runs until every checkpoint was reached, or the program halts, faults or runs out of cycles
+pub fn run_tests(machine: &mut Machine, checkpoints: &[Checkpoint], max_cycles: u64) -> (Vec<Outcome>, Stop) {
+    let mut outcomes = vec![Outcome::NotReached; checkpoints.len()];
+    let mut pending = checkpoints.len();
+
+    let stop = loop {
+        let address = machine.pc();
+        for (index, checkpoint) in checkpoints.iter().enumerate() {
+            if checkpoint.address == address && outcomes[index] == Outcome::NotReached {
+                outcomes[index] = evaluate(machine, checkpoint);
+                pending -= 1;
+            }
+        }
+        if pending == 0 {
+            break Stop::Halted(address);
+        }
+        if machine.cycles >= max_cycles {
+            break Stop::CycleLimit;
+        }
+
+        match machine.step() {
+            Ok(_) if machine.pc() == address => break Stop::Halted(address),
+            Ok(_) => (),
+            Err(e) => break Stop::Fault(address, e)
+        }
+    };
+
+    (outcomes, stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileParser::pars_file;
+    use crate::InstructionParser::pars_instructions;
+    use crate::InstructionSet::InstructionSet;
+    use crate::Preprocessor::Settings;
+    use crate::Simulator::{Memory, Region};
+
+    fn expect(text: &str, address: u64, label: &str) -> Expect {
+        Expect { text: text.to_string(), address, label: Some(label.to_string()), file: String::from("t.maasm"), line: 1, column: 5 }
+    }
+
+    #[test]
+    fn test_pars_checkpoints() {
+        let labels = HashMap::from([(String::from("count"), 0x2000)]);
+        let checkpoints = pars_checkpoints(&[
+            expect("%A0 == 0x10", 0x10, "done"),
+            expect("BYTE [count] <= 5", 0x10, "done"),
+            expect("%a1 != %A2", 0x20, "other"),
+            expect("[count + 4] > count - 1", 0x20, "other"),
+            expect("word [ count + 2 ] == 2 + 2", 0x20, "other")
+        ], &labels).unwrap();
+
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[0].name, "done");
+        assert_eq!(checkpoints[0].checks[0].left.1, Operand::Register(1));
+        assert_eq!(checkpoints[0].checks[1].left.1, Operand::Memory(0x2000, 1));
+        assert_eq!(checkpoints[0].checks[1].comparison, Comparison::LessEqual);
+        assert_eq!(checkpoints[1].checks[0].right.1, Operand::Register(3));
+        assert_eq!((checkpoints[1].checks[1].left.1.clone(), checkpoints[1].checks[1].right.1.clone()), (Operand::Memory(0x2004, 4), Operand::Number(0x1FFF)));
+        assert_eq!((checkpoints[1].checks[2].left.1.clone(), checkpoints[1].checks[2].right.1.clone()), (Operand::Memory(0x2002, 2), Operand::Number(4)));
+
+        let errors = pars_checkpoints(&[expect("%A9 == 1", 0, "a"), expect("[1] 2", 0, "a"), expect("QWORD [1] == 2", 0, "a")], &labels).unwrap_err();
+        assert_eq!(errors.iter().map(|d| d.to_string()).collect::<Vec<String>>(), vec![
+            Diagnostic::error("t.maasm", 1, 5, String::from(".EXPECT: Unknown register '%A9'.")).to_string(),
+            Diagnostic::error("t.maasm", 1, 5, String::from(".EXPECT: '[1] 2' has no comparison, expected one of == != < <= > >=.")).to_string(),
+            Diagnostic::error("t.maasm", 1, 5, String::from(".EXPECT: 'QWORD [1]' isn't a valid expression: Expected the end of the line, found '['.")).to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_run_tests() {
+        let path = std::env::temp_dir().join(format!("mycpuassembler_test_{}.maasm", std::process::id()));
+        std::fs::write(&path, concat!(
+            ".VAR BYTE count 0\n",
+            "    LOAD8 %a0, [0]\n",
+            "    LOAD8 %a1, count\n",
+            "loop:\n",
+            "    ADD %a0, %a0, [1]\n",
+            "    STORE8 %a0, %a1\n",
+            "    EQ %ar0, %a0, [4]\n",
+            "    ZJMP %ar0, loop\n",
+            "done:\n",
+            ".EXPECT %A0 == 4\n",
+            ".EXPECT BYTE [count] == 4\n",
+            ".EXPECT %A1 == 0x40\n",
+            "    JMP done\n",
+            "never:\n",
+            ".EXPECT %A0 == 0\n",
+            "    JMP never\n"
+        )).unwrap();
+
+        let settings = Settings { code_segment: 0, data_segment: 0x80, stack_segment: 0x1000, rom_size: None, include_paths: vec![] };
+        let asm_file = pars_file(path.to_string_lossy().to_string(), settings).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let isa = InstructionSet::load(None).unwrap();
+        let symbols = asm_file.symbols();
+        let checkpoints = pars_checkpoints(&asm_file.expects, &symbols).unwrap();
+        let image = pars_instructions(asm_file.instructions, asm_file.data, symbols, &isa).unwrap();
+        let mut memory = Memory::new(16, vec![Region::new("ram", 0, 0x10000, true)]).unwrap();
+        memory.load(0, &image.bytes).unwrap();
+        let mut machine = Machine::new(&isa, memory, 0);
+
+        let (outcomes, stop) = run_tests(&mut machine, &checkpoints, 1000);
+        assert_eq!(stop, Stop::Halted(0x18));
+        assert_eq!(checkpoints.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>(), vec!["done", "never"]);
+        match &outcomes[0] {
+            Outcome::Fail(d) => {
+                assert_eq!(d.len(), 1);
+                assert!(d[0].to_string().contains("expected %A1 == 0x40, but %A1 is 0x80"));
+            },
+            o => panic!("unexpected outcome {:?}", o)
+        }
+        assert_eq!(outcomes[1], Outcome::NotReached);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::InstructionParser::get_register_name;
+use crate::Simulator::{AccessKind, Step, ZERO};
+
+// The first signals, the registers follow in label order
+const CLK: usize = 0;
+const PC: usize = 1;
+const FETCH: usize = 2;
+const READ: usize = 3;
+const WRITE: usize = 4;
+const ADDR: usize = 5;
+const DATA: usize = 6;
+const SIZE: usize = 7;
+const REGISTERS: usize = 8;
+
+// Every cycle is two time units, clk rises at the start of a cycle and falls halfway
+pub struct Trace {
+    output: String,
+    values: Vec<Option<u64>>,
+    widths: Vec<u32>,
+    labels: Vec<usize>,
+    cycle: u64
+}
+
+// VCD identifiers are short strings of the printable characters ! to ~
+fn identifier(signal: usize) -> String {
+    let mut result = String::new();
+    let mut rest = signal;
+    loop {
+        result.push((b'!' + (rest % 94) as u8) as char);
+        rest /= 94;
+        if rest == 0 {
+            return result;
+        }
+        rest -= 1;
+    }
+}
+
+impl Trace {
+    // registers and pc are the state before the first instruction
+    pub fn new(registers: &[u32; 64], pc: u64) -> Trace {
+        let labels = (0..64).filter(|&l| get_register_name(l as u8).is_some()).collect::<Vec<usize>>();
+        let mut widths = vec![1, 32, 1, 1, 1, 32, 32, 3];
+        widths.extend(labels.iter().map(|_| 32));
+
+        let mut output = String::from("$version mycpuassembler $end\n$timescale 1ns $end\n");
+        let names = ["clk", "pc", "fetch", "read", "write", "addr", "data", "size"];
+        output += "$scope module macpu $end\n";
+        for (signal, name) in names.iter().enumerate() {
+            if signal == FETCH {
+                output += "$scope module bus $end\n";
+            }
+            output += &format!("$var wire {} {} {} $end\n", widths[signal], identifier(signal), name);
+        }
+        output += "$upscope $end\n$scope module registers $end\n";
+        for (index, &label) in labels.iter().enumerate() {
+            output += &format!("$var wire 32 {} {} $end\n", identifier(REGISTERS + index), get_register_name(label as u8).unwrap_or_default());
+        }
+        output += "$upscope $end\n$upscope $end\n$enddefinitions $end\n";
+
+        let mut trace = Trace { output, values: vec![None; widths.len()], widths, labels, cycle: 0 };
+        trace.output += "#0\n$dumpvars\n";
+        for signal in [CLK, FETCH, READ, WRITE, ADDR, DATA, SIZE] {
+            trace.change(signal, 0);
+        }
+        trace.change(PC, pc);
+        trace.registers(registers);
+        trace.output += "$end\n";
+
+        trace
+    }
+
+    fn change(&mut self, signal: usize, value: u64) {
+        if self.values[signal] == Some(value) {
+            return;
+        }
+
+        self.values[signal] = Some(value);
+        let id = identifier(signal);
+        match self.widths[signal] {
+            1 => self.output += &format!("{}{}\n", value, id),
+            _ => self.output += &format!("b{:b} {}\n", value, id)
+        }
+    }
+
+    fn registers(&mut self, registers: &[u32; 64]) {
+        for index in 0..self.labels.len() {
+            let label = self.labels[index];
+            let value = if label == ZERO { 0 } else { registers[label] };
+            self.change(REGISTERS + index, value as u64);
+        }
+    }
+
+    // one access per cycle, in order, the cycle of a taken jump has none.
+    // The registers change at the end of the last cycle of the instruction.
+    pub fn record(&mut self, step: &Step, registers: &[u32; 64]) {
+        for index in 0..step.cycles {
+            self.output += &format!("#{}\n", self.cycle * 2);
+            self.change(CLK, 1);
+            self.change(PC, step.address);
+
+            match step.accesses.get(index as usize) {
+                Some(access) => {
+                    self.change(FETCH, (access.kind == AccessKind::Fetch) as u64);
+                    self.change(READ, (access.kind == AccessKind::Read) as u64);
+                    self.change(WRITE, (access.kind == AccessKind::Write) as u64);
+                    self.change(ADDR, access.address);
+                    self.change(DATA, access.value as u64);
+                    self.change(SIZE, access.size as u64);
+                },
+                None => {
+                    self.change(FETCH, 0);
+                    self.change(READ, 0);
+                    self.change(WRITE, 0);
+                }
+            }
+
+            self.output += &format!("#{}\n", self.cycle * 2 + 1);
+            self.change(CLK, 0);
+            if index + 1 == step.cycles {
+                self.registers(registers);
+            }
+            self.cycle += 1;
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.output += &format!("#{}\n", self.cycle * 2);
+        self.change(FETCH, 0);
+        self.change(READ, 0);
+        self.change(WRITE, 0);
+
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Simulator::Access;
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert_eq!(identifier(95), "\"!");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut registers = [0; 64];
+        registers[crate::Simulator::PC] = 0x100;
+        let mut trace = Trace::new(&registers, 0x100);
+
+        // LOAD8 %a0, [0x40], [0] at 0x100
+        registers[crate::Simulator::PC] = 0x104;
+        registers[1] = 7;
+        let step = Step {
+            address: 0x100,
+            word: 0x12345678,
+            cycles: 2,
+            accesses: vec![
+                Access { kind: AccessKind::Fetch, address: 0x100, size: 4, value: 0x12345678 },
+                Access { kind: AccessKind::Read, address: 0x40, size: 1, value: 7 }
+            ]
+        };
+        trace.record(&step, &registers);
+        let output = trace.finish();
+
+        assert!(output.starts_with("$version mycpuassembler $end\n$timescale 1ns $end\n$scope module macpu $end\n$var wire 1 ! clk $end\n"));
+        assert!(output.contains("$scope module registers $end\n$var wire 32 ) ZERO $end\n$var wire 32 * A0 $end\n"));
+        let changes = &output[output.find("#0\n1!").unwrap()..];
+        assert_eq!(changes, concat!(
+            "#0\n1!\n1#\nb100000000 &\nb10010001101000101011001111000 '\nb100 (\n",
+            "#1\n0!\n",
+            "#2\n1!\n0#\n1$\nb1000000 &\nb111 '\nb1 (\n",
+            "#3\n0!\nb111 *\nb100000100 R\n",
+            "#4\n0$\n"
+        ));
+    }
+}
@@ -1,59 +1,614 @@
+#![allow(clippy::needless_return)]
+
 extern crate clap;
 extern crate tokio;
 
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::process;
 
+#[allow(non_snake_case)]
+mod Debugger;
+#[allow(non_snake_case)]
+mod Diagnostic;
+#[allow(non_snake_case)]
+mod Disassembler;
+#[allow(non_snake_case)]
 mod FileParser;
+#[allow(non_snake_case)]
+mod Formatter;
+#[allow(non_snake_case)]
+mod GdbServer;
+#[allow(non_snake_case)]
 mod InstructionParser;
+#[allow(non_snake_case)]
+mod InstructionSet;
+#[allow(non_snake_case)]
+mod IsaCheck;
+#[allow(non_snake_case)]
+mod LanguageServer;
+#[allow(non_snake_case)]
+mod Listing;
+#[allow(non_snake_case)]
+mod OutputFormat;
+#[allow(non_snake_case)]
+mod Preprocessor;
+#[allow(non_snake_case)]
+mod Simulator;
+#[allow(non_snake_case)]
+mod SymbolMap;
+#[allow(non_snake_case)]
+mod Syntax;
+#[allow(non_snake_case)]
+mod TestRunner;
+#[allow(non_snake_case)]
+mod Vcd;
 
-use clap::builder::Str;
-use clap::{Parser, command};
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(author = "Abonite", version = "0.1.1", about = None, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    input_file: String,
-    #[arg(short, long)]
-    output_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short, long, required = true)]
+    input_file: Option<String>,
+    #[arg(short, long, required = true)]
+    output_file: Option<String>,
+    #[command(flatten)]
+    segments: SegmentArgs,
+    #[arg(long, default_value_t = String::from("bin"))]
+    compile_mode: String,
+    #[arg(long, global = true)]
+    isa_file: Option<String>,
+    #[arg(long)]
+    entry: Option<String>,
+    #[arg(long, default_value_t = 32)]
+    word_width: u32,
+    #[arg(long)]
+    listing: Option<String>,
+    #[arg(long)]
+    map: Option<String>,
+    #[arg(long, default_value_t = String::from("text"), value_parser = ["text", "json"])]
+    map_format: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Turn a binary image back into assembly")]
+    Disasm(DisasmArgs),
+    #[command(about = "List the encoding of every instruction form and report forms that overlap")]
+    CheckIsa,
+    #[command(about = "Run a binary image on the MACPU simulator")]
+    Run(RunArgs),
+    #[command(about = "Assemble a source file and step through it on the simulator")]
+    Debug(DebugArgs),
+    #[command(about = "Serve the simulator to gdb over the remote serial protocol")]
+    Gdb(GdbArgs),
+    #[command(about = "Assemble source files and check their .EXPECT lines on the simulator")]
+    Test(TestArgs),
+    #[command(about = "Run a language server on stdin and stdout for editors")]
+    Lsp(LspArgs),
+    #[command(about = "Rewrite source files in the canonical style")]
+    Fmt(FmtArgs)
+}
+
+#[derive(clap::Args, Debug)]
+struct SegmentArgs {
     #[arg(long, default_value_t = 0)]
     code_start_addr: u16,
     #[arg(long, default_value_t = 0x1000)]
     stack_start_addr: u16,
     #[arg(long, default_value_t = 0x2000)]
     data_start_addr: u16,
-    #[arg(long, default_value_t = String::from("bin"))]
-    compile_mode: String,
+    #[arg(long)]
+    rom_size: Option<u64>,
+    // searched in order for .INCLUDE files that aren't next to the including file
+    #[arg(short = 'I', long = "include-path")]
+    include_paths: Vec<String>,
+}
+
+impl SegmentArgs {
+    fn settings(&self) -> Preprocessor::Settings {
+        Preprocessor::Settings {
+            code_segment: self.code_start_addr as u64,
+            data_segment: self.data_start_addr as u64,
+            stack_segment: self.stack_start_addr as u64,
+            rom_size: self.rom_size,
+            include_paths: self.include_paths.clone()
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct DisasmArgs {
+    #[arg(short, long)]
+    input_file: String,
+    // the listing is printed when no output file is given
+    #[arg(short, long)]
+    output_file: Option<String>,
+    #[arg(long, default_value_t = 0)]
+    base_addr: u64,
+    // a symbol map written with --map, in either format
+    #[arg(long)]
+    map: Option<String>,
+}
+
+// The default memory map is the one of the MACPU board: a 20 bit address bus, RAM
+// below the 64kB ROM at 0xF0000, and the reset vector in the last word of the ROM.
+#[derive(clap::Args, Debug)]
+struct MachineArgs {
+    #[arg(long, default_value_t = 20)]
+    address_bits: u32,
+    // repeat for every region, rom:BASE:SIZE or ram:BASE:SIZE
+    #[arg(long = "memory", default_values_t = [String::from("ram:0:0xF0000"), String::from("rom:0xF0000:0x10000")])]
+    memory: Vec<String>,
+    #[arg(long, value_parser = Preprocessor::pars_number, default_value = "0xFFFFC")]
+    reset_vector: u64,
+    #[arg(long, default_value_t = 1_000_000)]
+    max_cycles: u64,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    // a flat binary image, like the one the bin compile mode writes
+    #[arg(short, long)]
+    input_file: String,
+    #[arg(long, value_parser = Preprocessor::pars_number, default_value = "0")]
+    base_addr: u64,
+    // a value change dump of every cycle, for GTKWave
+    #[arg(long)]
+    vcd: Option<String>,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct DebugArgs {
+    // an assembly source file, its labels and lines are used by the debugger
+    #[arg(short, long)]
+    input_file: String,
+    #[command(flatten)]
+    segments: SegmentArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct GdbArgs {
+    // a flat binary image, gdb can also write the memory itself
+    #[arg(short, long)]
+    input_file: String,
+    #[arg(long, value_parser = Preprocessor::pars_number, default_value = "0")]
+    base_addr: u64,
+    #[arg(long, default_value_t = 1234)]
+    port: u16,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct TestArgs {
+    // repeat to run several files, each one on a fresh machine
+    #[arg(short, long, required = true)]
+    input_file: Vec<String>,
+    #[command(flatten)]
+    segments: SegmentArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct LspArgs {
+    // the segments open documents are assembled with
+    #[command(flatten)]
+    segments: SegmentArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct FmtArgs {
+    #[arg(short, long, required = true)]
+    input_file: Vec<String>,
+    // only report the files that aren't formatted, and fail if there are any
+    #[arg(long)]
+    check: bool,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let (asm_instructions, asm_labels) = FileParser::pars_file(args.input_file);
-    let bin_code = InstructionParser::pars_instructions(asm_instructions, asm_labels);
+    let isa = match InstructionSet::InstructionSet::load(args.isa_file.clone()) {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
 
-    write_bin(args.output_file, bin_code);
+    match args.command {
+        Some(Command::Disasm(disasm_args)) => disassemble(disasm_args, &isa),
+        Some(Command::CheckIsa) => check_isa(&isa),
+        Some(Command::Run(run_args)) => run(run_args, &isa),
+        Some(Command::Debug(debug_args)) => debug(debug_args, &isa),
+        Some(Command::Gdb(gdb_args)) => gdb(gdb_args, &isa),
+        Some(Command::Test(test_args)) => test(test_args, &isa),
+        Some(Command::Lsp(lsp_args)) => lsp(lsp_args, &isa),
+        Some(Command::Fmt(fmt_args)) => fmt(fmt_args, &isa),
+        None => assemble(args, &isa)
+    }
 }
 
-fn write_bin(output_file_path: String, bin_code: Vec<u8>) {
-    let output_file = match File::create(output_file_path) {
+fn assemble(args: Args, isa: &InstructionSet::InstructionSet) {
+    // clap makes sure both are given when there is no subcommand
+    let input_file = args.input_file.unwrap_or_default();
+    let output_file = args.output_file.unwrap_or_default();
+
+    let asm_file = match FileParser::pars_file(input_file.clone(), args.segments.settings()) {
         Ok(f) => f,
+        Err(d) => fail(d)
+    };
+    let names = asm_file.symbols();
+    let mut diagnostics = asm_file.warnings;
+    let rom_size = asm_file.settings.rom_size;
+
+    let entry = match args.entry {
+        Some(e) => match asm_file.labels.get(&e) {
+            Some(&a) => Some(a),
+            None => match Preprocessor::pars_number(&e) {
+                Ok(a) => Some(a),
+                Err(_) => {
+                    diagnostics.push(Diagnostic::Diagnostic::error(&input_file, 0, 0, format!("Entry '{}' is neither a label nor an address.", e)));
+                    fail(diagnostics)
+                }
+            }
+        },
+        None => None
+    };
+
+    let symbols = SymbolMap::collect_symbols(&asm_file.labels, &asm_file.data);
+
+    // pars_instructions consumes the instructions, the listing needs them afterwards
+    let listed = match args.listing {
+        Some(_) => asm_file.instructions.clone(),
+        None => vec![]
+    };
+
+    let bin_code = match InstructionParser::pars_instructions(asm_file.instructions, asm_file.data, names, isa) {
+        Ok(b) => b,
+        Err(mut d) => {
+            diagnostics.append(&mut d);
+            fail(diagnostics)
+        }
+    };
+
+    // every output is made before the first file is written, an error leaves no partial output
+    let output = match args.compile_mode.as_str() {
+        "bin" => Ok(bin_code.bytes.clone()),
+        "ihex" => OutputFormat::to_ihex(&bin_code, entry).map(|s| s.into_bytes()),
+        "srec" => OutputFormat::to_srec(&bin_code, entry, &output_file).map(|s| s.into_bytes()),
+        "readmemh" => OutputFormat::to_readmem(&bin_code, args.word_width, false).map(|s| s.into_bytes()),
+        "readmemb" => OutputFormat::to_readmem(&bin_code, args.word_width, true).map(|s| s.into_bytes()),
+        "coe" => OutputFormat::to_coe(&bin_code, args.word_width, rom_size).map(|s| s.into_bytes()),
+        "mif" => OutputFormat::to_mif(&bin_code, args.word_width, rom_size).map(|s| s.into_bytes()),
+        _ => Err(format!("Unknown compile mode '{}', expected one of bin, ihex, srec, readmemh, readmemb, coe, mif.", args.compile_mode))
+    };
+    let output = match output {
+        Ok(o) => o,
         Err(e) => {
-            println!("{}", e);
-            panic!();
+            diagnostics.push(Diagnostic::Diagnostic::error(&input_file, 0, 0, e));
+            fail(diagnostics)
         }
     };
 
-    let mut writer = BufWriter::new(output_file);
+    // clap only lets text and json through
+    let map = match args.map_format.as_str() {
+        "json" => SymbolMap::to_json(&symbols),
+        _ => SymbolMap::to_text(&symbols)
+    };
 
-    let bin_code = bin_code.as_slice();
-    match writer.write_all(bin_code) {
-        Ok(_) => (),
-        Err(e) => {
-            println!("{}", e);
-            panic!();
+    if let Some(listing_file) = &args.listing {
+        let listing = Listing::to_listing(&listed, &asm_file.marks, &bin_code, |f| std::fs::read_to_string(f).ok());
+        if let Err(e) = write_bin(listing_file, listing.into_bytes()) {
+            diagnostics.push(Diagnostic::Diagnostic::error(listing_file, 0, 0, e.to_string()));
+            fail(diagnostics);
+        }
+    }
+
+    if let Some(map_file) = &args.map {
+        if let Err(e) = write_bin(map_file, map.into_bytes()) {
+            diagnostics.push(Diagnostic::Diagnostic::error(map_file, 0, 0, e.to_string()));
+            fail(diagnostics);
         }
+    }
+
+    if let Err(e) = write_bin(&output_file, output) {
+        diagnostics.push(Diagnostic::Diagnostic::error(&output_file, 0, 0, e.to_string()));
+        fail(diagnostics);
+    }
+
+    Diagnostic::print_all(&diagnostics);
+}
+
+fn disassemble(args: DisasmArgs, isa: &InstructionSet::InstructionSet) {
+    let bytes = match std::fs::read(&args.input_file) {
+        Ok(b) => b,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e.to_string())])
     };
+
+    let symbols = match &args.map {
+        Some(map_file) => match std::fs::read_to_string(map_file).map_err(|e| e.to_string()).and_then(|m| Disassembler::pars_symbols(&m)) {
+            Ok(s) => s,
+            Err(e) => fail(vec![Diagnostic::Diagnostic::error(map_file, 0, 0, e)])
+        },
+        None => vec![]
+    };
+
+    let listing = Disassembler::disassemble(&bytes, args.base_addr, isa, &symbols);
+    match &args.output_file {
+        Some(output_file) => {
+            if let Err(e) = write_bin(output_file, listing.into_bytes()) {
+                fail(vec![Diagnostic::Diagnostic::error(output_file, 0, 0, e.to_string())]);
+            }
+        },
+        None => print!("{}", listing)
+    }
+}
+
+fn check_isa(isa: &InstructionSet::InstructionSet) {
+    for inst in isa.instructions() {
+        for form in inst.forms.iter() {
+            println!("{:<8} {:<3} {}  {}", inst.name, form.kind.name(), form.args, IsaCheck::bit_pattern(form, &inst.constraint));
+        }
+    }
+    println!();
+
+    let problems = IsaCheck::check(isa);
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("no overlapping encodings found");
+    } else {
+        println!("{} problem(s) found", problems.len());
+        process::exit(1);
+    }
+}
+
+fn build_memory(args: &MachineArgs, image: &[u8], base: u64) -> Result<Simulator::Memory, String> {
+    let mut regions = vec![];
+    for region in args.memory.iter() {
+        regions.push(Simulator::Region::pars(region)?);
+    }
+
+    let mut memory = Simulator::Memory::new(args.address_bits, regions)?;
+    memory.load(base, image)?;
+    Ok(memory)
+}
+
+fn run(args: RunArgs, isa: &InstructionSet::InstructionSet) {
+    let image = match std::fs::read(&args.input_file) {
+        Ok(b) => b,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e.to_string())])
+    };
+
+    let memory = match build_memory(&args.machine, &image, args.base_addr) {
+        Ok(m) => m,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e)])
+    };
+
+    let mut machine = Simulator::Machine::new(isa, memory, args.machine.reset_vector);
+    let stop = match &args.vcd {
+        Some(vcd_file) => {
+            let mut trace = Vcd::Trace::new(&machine.registers, machine.pc());
+            let stop = machine.run_traced(args.machine.max_cycles, |step, registers| trace.record(step, registers));
+            if let Err(e) = write_bin(vcd_file, trace.finish().into_bytes()) {
+                fail(vec![Diagnostic::Diagnostic::error(vcd_file, 0, 0, e.to_string())]);
+            }
+            stop
+        },
+        None => machine.run(args.machine.max_cycles)
+    };
+    println!("{}", stop);
+    println!("{} instruction(s), {} cycle(s)", machine.instructions, machine.cycles);
+    print!("{}", machine.register_dump());
+    Diagnostic::print_all(&simulator_warnings(&machine.warnings, &args.input_file, &[]));
+
+    if let Simulator::Stop::Fault(_, _) = stop {
+        process::exit(1);
+    }
+}
+
+// Words the simulator ran as one of several instructions, at their source line when it is known
+fn simulator_warnings(warnings: &[(u64, String)], input_file: &str, instructions: &[FileParser::Instr]) -> Vec<Diagnostic::Diagnostic> {
+    warnings.iter().map(|(address, message)| match instructions.iter().find(|i| i.address == *address) {
+        Some(i) => {
+            let (column, length) = i.span(0, i.data.chars().count() as u64);
+            Diagnostic::Diagnostic::warning(&i.file, i.line, column, message.clone()).with_length(length)
+        },
+        None => Diagnostic::Diagnostic::warning(input_file, 0, 0, format!("at {:#010X}: {}", address, message))
+    }).collect()
+}
+
+// A source file assembled for the simulator
+struct Program {
+    image: InstructionParser::Image,
+    labels: std::collections::HashMap<String, u64>,
+    // labels and constants, for .EXPECT
+    names: std::collections::HashMap<String, u64>,
+    instructions: Vec<FileParser::Instr>,
+    expects: Vec<FileParser::Expect>
+}
+
+// Warnings are printed right away, errors end the program
+fn assemble_program(input_file: &str, segments: &SegmentArgs, isa: &InstructionSet::InstructionSet) -> Program {
+    let asm_file = match FileParser::pars_file(input_file.to_string(), segments.settings()) {
+        Ok(f) => f,
+        Err(d) => fail(d)
+    };
+    let names = asm_file.symbols();
+    let mut diagnostics = asm_file.warnings;
+
+    let labels = asm_file.labels.clone();
+    let instructions = asm_file.instructions.clone();
+    let image = match InstructionParser::pars_instructions(asm_file.instructions, asm_file.data, names.clone(), isa) {
+        Ok(i) => i,
+        Err(mut d) => {
+            diagnostics.append(&mut d);
+            fail(diagnostics)
+        }
+    };
+
+    Diagnostic::print_all(&diagnostics);
+    Program { image, labels, names, instructions, expects: asm_file.expects }
+}
+
+fn debug(args: DebugArgs, isa: &InstructionSet::InstructionSet) {
+    let program = assemble_program(&args.input_file, &args.segments, isa);
+
+    let memory = match build_memory(&args.machine, &program.image.bytes, 0) {
+        Ok(m) => m,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e)])
+    };
+
+    let machine = Simulator::Machine::new(isa, memory, args.machine.reset_vector);
+    let mut debugger = Debugger::Debugger::new(machine, program.labels, &program.instructions, args.machine.max_cycles, |f| std::fs::read_to_string(f).ok());
+    if let Some(output) = debugger.command("where") {
+        println!("{}", output);
+    }
+
+    let stdin = std::io::stdin();
+    let mut reported = 0;
+    loop {
+        print!("(madbg) ");
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => ()
+        }
+        match debugger.command(&line) {
+            Some(output) if output.is_empty() => (),
+            Some(output) => println!("{}", output),
+            None => break
+        }
+
+        let warnings = debugger.machine.warnings.len();
+        if warnings > reported {
+            Diagnostic::print_all(&simulator_warnings(&debugger.machine.warnings[reported..], &args.input_file, &program.instructions));
+            reported = warnings;
+        }
+    }
+}
+
+fn test(args: TestArgs, isa: &InstructionSet::InstructionSet) {
+    let (mut passed, mut failed) = (0, 0);
+    for input_file in args.input_file.iter() {
+        let program = assemble_program(input_file, &args.segments, isa);
+        let checkpoints = match TestRunner::pars_checkpoints(&program.expects, &program.names) {
+            Ok(c) => c,
+            Err(d) => fail(d)
+        };
+
+        let memory = match build_memory(&args.machine, &program.image.bytes, 0) {
+            Ok(m) => m,
+            Err(e) => fail(vec![Diagnostic::Diagnostic::error(input_file, 0, 0, e)])
+        };
+        let mut machine = Simulator::Machine::new(isa, memory, args.machine.reset_vector);
+        let (outcomes, stop) = TestRunner::run_tests(&mut machine, &checkpoints, args.machine.max_cycles);
+        Diagnostic::print_all(&simulator_warnings(&machine.warnings, input_file, &program.instructions));
+
+        println!("{}: {} test(s)", input_file, checkpoints.len());
+        for (checkpoint, outcome) in checkpoints.iter().zip(outcomes.iter()) {
+            println!("    {:<18} {} at {:#010X}", outcome.to_string(), checkpoint.name, checkpoint.address);
+            match outcome {
+                TestRunner::Outcome::Pass => passed += 1,
+                TestRunner::Outcome::Fail(d) => {
+                    Diagnostic::print_all(d);
+                    failed += 1;
+                },
+                TestRunner::Outcome::NotReached => {
+                    println!("    the program stopped first, {}", stop);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn fmt(args: FmtArgs, isa: &InstructionSet::InstructionSet) {
+    let mut unformatted = 0;
+    for input_file in args.input_file.iter() {
+        let source = match std::fs::read_to_string(input_file) {
+            Ok(s) => s,
+            Err(e) => fail(vec![Diagnostic::Diagnostic::error(input_file, 0, 0, e.to_string())])
+        };
+
+        let formatted = Formatter::format(&source, isa);
+        let line = match Formatter::first_difference(&source, &formatted) {
+            Some(l) => l,
+            None => continue
+        };
+        if args.check {
+            println!("{}:{}: not formatted", input_file, line);
+            unformatted += 1;
+        } else if let Err(e) = write_bin(input_file, formatted.into_bytes()) {
+            fail(vec![Diagnostic::Diagnostic::error(input_file, 0, 0, e.to_string())]);
+        }
+    }
+
+    if unformatted > 0 {
+        process::exit(1);
+    }
+}
+
+fn lsp(args: LspArgs, isa: &InstructionSet::InstructionSet) {
+    let mut server = LanguageServer::Server::new(isa, args.segments.settings());
+    if let Err(e) = LanguageServer::serve(&mut server, &mut std::io::stdin().lock(), &mut std::io::stdout()) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+    // exit without a shutdown before it is an error for LSP
+    if !server.is_shut_down() {
+        process::exit(1);
+    }
+}
+
+fn gdb(args: GdbArgs, isa: &InstructionSet::InstructionSet) {
+    let image = match std::fs::read(&args.input_file) {
+        Ok(b) => b,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e.to_string())])
+    };
+
+    let memory = match build_memory(&args.machine, &image, args.base_addr) {
+        Ok(m) => m,
+        Err(e) => fail(vec![Diagnostic::Diagnostic::error(&args.input_file, 0, 0, e)])
+    };
+
+    let machine = Simulator::Machine::new(isa, memory, args.machine.reset_vector);
+    let mut target = GdbServer::Target::new(machine, args.machine.max_cycles);
+    if let Err(e) = GdbServer::serve(&mut target, args.port) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn fail(diagnostics: Vec<Diagnostic::Diagnostic>) -> ! {
+    Diagnostic::print_all(&diagnostics);
+    process::exit(1);
+}
+
+fn write_bin(output_file_path: &str, bin_code: Vec<u8>) -> std::io::Result<()> {
+    let output_file = File::create(output_file_path)?;
+
+    let mut writer = BufWriter::new(output_file);
+    writer.write_all(bin_code.as_slice())?;
+    writer.flush()
 }